@@ -0,0 +1,180 @@
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand};
+use lora_host_common::{
+    gateway::{Baudrate, GatewayDriver},
+    logging::{self, LogArgs},
+    metrics,
+    ota::{self, Phase, BLOCK_SIZE},
+    schema,
+    selftest::{self, Check},
+    term::{self, Color, Phases},
+};
+use std::path::PathBuf;
+
+/// LoRa module host tools sharing one gateway connection setup
+#[derive(Parser)]
+struct Args {
+    /// The gateway's serialport, e.g. /dev/ttyACM0 or COM3, `auto` picks the only USB one
+    #[clap(short, long, global = true)]
+    port: Option<String>,
+
+    /// The baudrate to open the port with, `auto` tries the common ones
+    #[clap(short, long, global = true, default_value = "115200")]
+    baudrate: Baudrate,
+
+    #[clap(flatten)]
+    log: LogArgs,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check that the gateway answers and show the round trip time
+    Ping,
+    /// Read the soil moisture sensor of a node once
+    Sensor {
+        /// The node address
+        destination_address: usize,
+    },
+    /// Update the firmware of a node over the air
+    Update {
+        /// The node address
+        destination_address: usize,
+
+        /// Path to the firmware binary
+        binary: PathBuf,
+
+        /// Diagnostic file output path, JSON lines with block snapshots when it ends in .jsonl
+        #[clap(long)]
+        debug_file: Option<PathBuf>,
+
+        /// Log every block sent and acknowledged instead of drawing a progress bar
+        #[clap(short, long)]
+        verbose: bool,
+    },
+    /// List the packets of the gateway-host-schema this binary was built with
+    Schema,
+    /// Exercise the codec and the link to the gateway, report what fails
+    Selftest {
+        /// Pings sent one at a time and back to back
+        #[clap(long, default_value = "10")]
+        count: usize,
+
+        /// Only check the codec, no gateway needed
+        #[clap(long)]
+        codec_only: bool,
+    },
+}
+
+impl Args {
+    /// Opens the port and makes sure a gateway answers on it
+    fn connect(&self) -> Result<GatewayDriver> {
+        let port = self
+            .port
+            .as_deref()
+            .ok_or(anyhow!("--port is required for this command"))?;
+        let mut gateway = GatewayDriver::open(port, self.baudrate)
+            .with_context(|| format!("Failed to open port {}", port))?;
+        gateway
+            .ping()
+            .with_context(|| format!("Failed to connect to Gateway on {}", port))?;
+        Ok(gateway)
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    logging::init(&args.log)?;
+    let result = run(&args);
+    metrics::log_report();
+    result
+}
+
+fn run(args: &Args) -> Result<()> {
+    match &args.command {
+        Command::Ping => {
+            let latency = args.connect()?.ping()?;
+            println!(
+                "{}",
+                term::paint(
+                    format!("Gateway answered in {} ms", latency.as_millis()),
+                    Color::Green
+                )
+            );
+            Ok(())
+        }
+        Command::Sensor {
+            destination_address,
+        } => {
+            let moisture = args.connect()?.soil_sensor(*destination_address)?;
+            println!(
+                "Node {}: {}",
+                destination_address,
+                moisture
+                    .iter()
+                    .map(|m| m.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+            Ok(())
+        }
+        Command::Update {
+            destination_address,
+            binary,
+            debug_file,
+            verbose,
+        } => {
+            let firmware = ota::map_binary(binary)?;
+            let mut debug = debug_file.as_deref().map(ota::debug_log).transpose()?;
+            let options = ota::Options::default();
+            let mut display = term::transfer(options.block_size, *verbose);
+            let mut phases = Phases::new();
+            phases.enter(Phase::Connect);
+            let result = args.connect().and_then(|mut gateway| {
+                ota::update(
+                    &mut gateway,
+                    *destination_address,
+                    &firmware,
+                    &options,
+                    &mut ((&mut debug, &mut phases), &mut display),
+                )
+            });
+            let title = match result {
+                Ok(()) => format!("Node {} updated", destination_address),
+                Err(_) => format!("Node {} was not updated", destination_address),
+            };
+            phases.print(&title, &result);
+            result
+        }
+        Command::Schema => schema::print(BLOCK_SIZE),
+        Command::Selftest { count, codec_only } => {
+            let mut checks = vec![selftest::codec()];
+            if !codec_only {
+                let mut gateway = args.connect()?;
+                checks.push(selftest::ping(&mut gateway, *count));
+                checks.push(selftest::burst(&mut gateway, *count));
+                checks.push(selftest::status(&mut gateway));
+            }
+            report(&checks)
+        }
+    }
+}
+
+fn report(checks: &[Check]) -> Result<()> {
+    for check in checks {
+        let result = match check.passed() {
+            true => term::paint(format!("{:<4}", "ok"), Color::Green),
+            false => term::paint("FAIL", Color::Red),
+        };
+        println!("{} {:<8} {}", result, check.name, check.summary);
+        for failure in &check.failures {
+            println!("       {}", failure);
+        }
+    }
+    match checks.iter().filter(|c| !c.passed()).count() {
+        0 => Ok(()),
+        failed => Err(anyhow!("{} of {} checks failed", failed, checks.len())),
+    }
+}
@@ -0,0 +1,81 @@
+//! Frames per second the driver decodes from a pty, against reading a byte per syscall the
+//! way it used to. Run with `cargo bench -p lora-host-common`.
+
+use gateway_host_schema::GatewayPacket;
+use lora_host_common::{
+    codec::{self, Decoder, MAX_FRAME},
+    gateway::GatewayDriver,
+};
+use serialport::{SerialPort, TTYPort};
+use std::{
+    io::{Read, Write},
+    sync::mpsc::{self, Receiver},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+const FRAMES: usize = 20_000;
+
+/// Writes `FRAMES` moisture readings back to back, as fast as the pty takes them
+fn gateway(mut port: TTYPort, done: Receiver<()>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut buffer = [0u8; MAX_FRAME];
+        let encoded = postcard::to_slice(
+            &GatewayPacket::SoilSensorMoisture([310, 420, 530, 640]),
+            &mut buffer,
+        )
+        .unwrap();
+        let mut frame = [0u8; MAX_FRAME];
+        let len = codec::encode(encoded, &mut frame).unwrap();
+        let stream = frame[..len].repeat(FRAMES);
+        port.write_all(&stream).unwrap();
+        // closing the master would hang up the reader
+        done.recv().unwrap();
+    })
+}
+
+fn bytewise(mut port: TTYPort) -> usize {
+    port.set_timeout(Duration::from_secs(1)).unwrap();
+    let mut decoder = Decoder::new();
+    let mut frames = 0;
+    while frames < FRAMES {
+        let mut recv = [0u8; 1];
+        port.read_exact(&mut recv).unwrap();
+        if decoder.push(recv[0]).unwrap() {
+            postcard::from_bytes::<GatewayPacket>(decoder.frame()).unwrap();
+            decoder = Decoder::new();
+            frames += 1;
+        }
+    }
+    frames
+}
+
+fn buffered(port: TTYPort) -> usize {
+    let mut gateway = GatewayDriver::from_port(Box::new(port));
+    (0..FRAMES)
+        .map(|_| gateway.read_with_timeout(Duration::from_secs(1)).unwrap())
+        .count()
+}
+
+fn run(name: &str, read: fn(TTYPort) -> usize) {
+    let (master, slave) = TTYPort::pair().unwrap();
+    let (done, wait) = mpsc::channel();
+    let writer = gateway(master, wait);
+    let start = Instant::now();
+    let frames = read(slave);
+    let elapsed = start.elapsed();
+    done.send(()).unwrap();
+    writer.join().unwrap();
+    println!(
+        "{:<10} {} frames in {:>7.1} ms, {:>9.0} frames/s",
+        name,
+        frames,
+        elapsed.as_secs_f64() * 1e3,
+        frames as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    run("bytewise", bytewise);
+    run("buffered", buffered);
+}
@@ -0,0 +1,166 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+pub const DEFAULT_PATH: &str = "addressbook.json";
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    pub address: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+}
+
+/// Node addresses by name, shared by the updater and the sensor reader so both can be told
+/// `greenhouse-2` instead of a number
+pub struct AddressBook {
+    path: PathBuf,
+    pub entries: BTreeMap<String, Entry>,
+}
+
+impl AddressBook {
+    /// Opens the address book, an absent file is an empty one
+    pub fn load(path: &Path) -> Result<AddressBook> {
+        let entries = match std::fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)
+                .with_context(|| format!("Failed to parse the address book {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read the address book {}", path.display()))
+            }
+        };
+        Ok(AddressBook {
+            path: path.to_owned(),
+            entries,
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        std::fs::write(&self.path, serde_json::to_string_pretty(&self.entries)?)
+            .with_context(|| format!("Failed to write the address book {}", self.path.display()))
+    }
+
+    pub fn address(&self, node: &NodeRef) -> Result<usize> {
+        match node {
+            NodeRef::Address(address) => Ok(*address),
+            NodeRef::Name(name) => self.entries.get(name).map(|e| e.address).ok_or(anyhow!(
+                "No node is named {} in {}",
+                name,
+                self.path.display()
+            )),
+        }
+    }
+
+    /// Adds a name or moves it to another address, notes are kept unless new ones are given
+    pub fn set(&mut self, name: &str, address: usize, notes: Option<String>) -> Result<()> {
+        check_name(name)?;
+        if let Some((other, _)) = self
+            .entries
+            .iter()
+            .find(|(n, e)| e.address == address && *n != name)
+        {
+            return Err(anyhow!("Node {} is already named {}", address, other));
+        }
+        let notes = notes.or(self.entries.remove(name).and_then(|e| e.notes));
+        self.entries
+            .insert(name.to_owned(), Entry { address, notes });
+        Ok(())
+    }
+
+    /// Edits the address book as asked on the command line, lists it without an action
+    pub fn manage(&mut self, action: Option<AddressBookAction>) -> Result<()> {
+        match action {
+            Some(AddressBookAction::Set {
+                name,
+                address,
+                notes,
+            }) => {
+                self.set(&name, address, notes)?;
+                self.save()
+            }
+            Some(AddressBookAction::Remove { name }) => match self.entries.remove(&name) {
+                Some(_) => self.save(),
+                None => Err(anyhow!(
+                    "No node is named {} in {}",
+                    name,
+                    self.path.display()
+                )),
+            },
+            None => {
+                println!("{:<20} {:>8}  NOTES", "NAME", "ADDRESS");
+                for (name, entry) in &self.entries {
+                    println!(
+                        "{:<20} {:>8}  {}",
+                        name,
+                        entry.address,
+                        entry.notes.as_deref().unwrap_or("")
+                    );
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Names must not be taken for an address or a tag
+fn check_name(name: &str) -> Result<()> {
+    match name.parse::<NodeRef>()? {
+        NodeRef::Name(_) => Ok(()),
+        NodeRef::Address(_) => Err(anyhow!("A name cannot be a number, got {:?}", name)),
+    }
+}
+
+#[derive(clap::Subcommand)]
+pub enum AddressBookAction {
+    /// Name a node, or give a name another address
+    Set {
+        name: String,
+
+        address: usize,
+
+        #[clap(long)]
+        notes: Option<String>,
+    },
+    /// Forget a name
+    Remove { name: String },
+}
+
+/// A node address or its name in the address book
+#[derive(Clone, Debug, PartialEq)]
+pub enum NodeRef {
+    Address(usize),
+    Name(String),
+}
+
+impl FromStr for NodeRef {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(address) = s.parse() {
+            return Ok(NodeRef::Address(address));
+        }
+        match s.is_empty() || s.contains(':') || s.contains(char::is_whitespace) {
+            true => Err(anyhow!(
+                "expected a node address or a name without spaces or colons, got {:?}",
+                s
+            )),
+            false => Ok(NodeRef::Name(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for NodeRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeRef::Address(address) => write!(f, "{}", address),
+            NodeRef::Name(name) => f.write_str(name),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use crate::gateway::GatewayError;
+
+/// Bytes from this value up are sent as `ESCAPE` followed by their offset from it
+pub const ESCAPE: u8 = 254;
+/// Ends every frame, it never appears inside one thanks to the escaping
+pub const TERMINATOR: u8 = 0xff;
+/// Largest frame either side buffers
+pub const MAX_FRAME: usize = 256;
+
+/// Escapes `data` into `frame` and terminates it, returns the length of the frame
+pub fn encode(data: &[u8], frame: &mut [u8]) -> Result<usize, GatewayError> {
+    let mut j = 0;
+    for byte in data {
+        let escaped = *byte >= ESCAPE;
+        // leave room for the terminator
+        if j + escaped as usize + 1 >= frame.len() {
+            return Err(GatewayError::Overflow);
+        }
+        if escaped {
+            frame[j] = ESCAPE;
+            frame[j + 1] = byte - ESCAPE;
+            j += 2;
+        } else {
+            frame[j] = *byte;
+            j += 1;
+        }
+    }
+    frame[j] = TERMINATOR;
+    Ok(j + 1)
+}
+
+/// Unescapes a frame as it is received, a byte or a chunk at a time
+pub struct Decoder {
+    buffer: [u8; MAX_FRAME],
+    len: usize,
+    escaped: bool,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self {
+            buffer: [0u8; MAX_FRAME],
+            len: 0,
+            escaped: false,
+        }
+    }
+
+    /// Feeds one received byte, returns true once the terminator completed the frame
+    pub fn push(&mut self, byte: u8) -> Result<bool, GatewayError> {
+        if byte == TERMINATOR {
+            return Ok(true);
+        }
+        if self.len >= self.buffer.len() {
+            return Err(GatewayError::Overflow);
+        }
+        if byte == ESCAPE && !self.escaped {
+            self.escaped = true;
+            return Ok(false);
+        }
+        self.buffer[self.len] = match self.escaped {
+            true => byte
+                .checked_add(ESCAPE)
+                .ok_or(GatewayError::InvalidResponse)?,
+            false => byte,
+        };
+        self.len += 1;
+        self.escaped = false;
+        Ok(false)
+    }
+
+    /// Feeds received bytes until one of them completes the frame, returns how many were
+    /// taken then so the rest can start the next frame, None when all went into this one
+    pub fn push_slice(&mut self, bytes: &[u8]) -> Result<Option<usize>, GatewayError> {
+        for (i, byte) in bytes.iter().enumerate() {
+            if self.push(*byte)? {
+                return Ok(Some(i + 1));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The unescaped bytes received so far
+    pub fn frame(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
@@ -0,0 +1,509 @@
+use crate::{
+    codec::{self, Decoder, MAX_FRAME, TERMINATOR},
+    metrics,
+};
+use anyhow::{anyhow, Context, Result};
+use gateway_host_schema::{GatewayPacket, HostPacket, SoilSensorRequest};
+use serde::Deserialize;
+use serialport::{SerialPort, SerialPortType};
+use std::{
+    fmt,
+    io::ErrorKind,
+    str::FromStr,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tracing::{debug, info};
+
+/// Port name that makes [`GatewayDriver::new`] look the gateway up with [`find_port`]
+pub const AUTO_PORT: &str = "auto";
+
+#[derive(Error, Debug)]
+pub enum GatewayError {
+    #[error("A timeout was exceeded when receiving data from the Gateway: {0}")]
+    ReadTimeout(std::io::Error),
+    #[error("Gateway or host sent too much data")]
+    Overflow,
+    #[error("The {len} byte frame is longer than the {max} bytes the gateway buffers")]
+    FrameTooLong { len: usize, max: usize },
+    #[error("Serialization or deserialization of data failed: {0}")]
+    SerDe(postcard::Error),
+    #[error("Invalid response given by the gateway")]
+    InvalidResponse,
+    #[error("Expected {expected} from the gateway but got {got}")]
+    UnexpectedPacket { expected: &'static str, got: String },
+    #[error("This does not look like a gateway, {0}")]
+    NotAGateway(String),
+}
+
+impl GatewayError {
+    pub fn unexpected(expected: &'static str, got: &GatewayPacket) -> GatewayError {
+        GatewayError::UnexpectedPacket {
+            expected,
+            got: format!("{:?}", got),
+        }
+    }
+
+    /// Whether the gateway did not answer in time, the one failure worth repeating a request
+    /// for, a retry predicate for [`crate::retry::RetryPolicy::run_if`]
+    pub fn is_timeout(e: &anyhow::Error) -> bool {
+        matches!(e.downcast_ref(), Some(GatewayError::ReadTimeout(_)))
+    }
+}
+
+/// Rates tried by [`Baudrate::Auto`], most likely first
+pub const PROBE_BAUDRATES: [u32; 8] = [115200, 921600, 460800, 230400, 57600, 38400, 19200, 9600];
+
+/// A fixed baudrate or `auto` to probe for it
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "RawBaudrate")]
+pub enum Baudrate {
+    Fixed(u32),
+    Auto,
+}
+
+impl FromStr for Baudrate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Baudrate::Auto),
+            rate => rate
+                .parse()
+                .map(Baudrate::Fixed)
+                .map_err(|_| anyhow!("expected a baudrate or auto, got {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Baudrate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Baudrate::Fixed(rate) => write!(f, "{}", rate),
+            Baudrate::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// Numbers in config files are fixed rates
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawBaudrate {
+    Rate(u32),
+    Text(String),
+}
+
+impl TryFrom<RawBaudrate> for Baudrate {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawBaudrate) -> Result<Self> {
+        match raw {
+            RawBaudrate::Rate(rate) => Ok(Baudrate::Fixed(rate)),
+            RawBaudrate::Text(text) => text.parse(),
+        }
+    }
+}
+
+/// Most bytes taken from the port per read, a few frames' worth so an OTA status burst costs
+/// one syscall rather than one per byte
+const READ_CHUNK: usize = 4 * MAX_FRAME;
+/// How long a suspicious port gets to answer the query [`GatewayDriver::ping`] checks it with,
+/// a gateway busy with radio traffic can take a while
+const ECHO_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct GatewayDriver {
+    port: Box<dyn SerialPort>,
+    timeout: Duration,
+    /// Longest frame the gateway takes, see [`Self::set_max_frame`]
+    max_frame: usize,
+    /// Read from the port but not yet decoded
+    received: Vec<u8>,
+}
+
+/// Picks the only USB serial port attached, the enumeration works the same for
+/// `/dev/ttyACM*` devices and for Windows `COMx` ports
+pub fn find_port() -> Result<String> {
+    let ports = serialport::available_ports().context("Failed to enumerate serial ports")?;
+    let mut usb = ports.into_iter().filter_map(|p| match p.port_type {
+        SerialPortType::UsbPort(info) => Some((p.port_name, info)),
+        _ => None,
+    });
+    match (usb.next(), usb.next()) {
+        (Some((name, _)), None) => Ok(name),
+        (None, _) => Err(anyhow!(
+            "No USB serial port found, is the gateway plugged in?"
+        )),
+        (Some(first), Some(second)) => Err(anyhow!(
+            "Several USB serial ports found, pick one with --port: {}",
+            [first, second]
+                .into_iter()
+                .chain(usb)
+                .map(|(name, info)| format!(
+                    "{} ({:04x}:{:04x} {})",
+                    name,
+                    info.vid,
+                    info.pid,
+                    info.product.unwrap_or_default()
+                ))
+                .collect::<Vec<String>>()
+                .join(", ")
+        )),
+    }
+}
+
+impl GatewayDriver {
+    /// Opens the gateway on `path`, e.g. `/dev/ttyACM0` or `COM3`, or on the port found by
+    /// [`find_port`] when `path` is [`AUTO_PORT`]
+    pub fn new(path: &str, baudrate: u32) -> Result<GatewayDriver> {
+        let path = match path {
+            AUTO_PORT => {
+                let found = find_port()?;
+                info!("Using the gateway on {}", found);
+                found
+            }
+            path => path.to_owned(),
+        };
+        Ok(GatewayDriver {
+            // the USB CDC gateway only talks once DTR is raised, which Linux does on open
+            // but Windows does not
+            port: serialport::new(path, baudrate)
+                .timeout(Duration::from_millis(100))
+                .dtr_on_open(true)
+                .open()?,
+            timeout: Duration::from_millis(100),
+            max_frame: MAX_FRAME,
+            received: Vec::new(),
+        })
+    }
+
+    /// Opens the gateway at a fixed baudrate, or pings it at each of [`PROBE_BAUDRATES`]
+    /// and keeps the first one it answers at
+    pub fn open(path: &str, baudrate: Baudrate) -> Result<GatewayDriver> {
+        if let Baudrate::Fixed(rate) = baudrate {
+            return GatewayDriver::new(path, rate);
+        }
+        let path = match path {
+            AUTO_PORT => find_port()?,
+            path => path.to_owned(),
+        };
+        for rate in PROBE_BAUDRATES {
+            let answered = GatewayDriver::new(&path, rate).and_then(|mut gateway| {
+                gateway.ping()?;
+                Ok(gateway)
+            });
+            match answered {
+                Ok(gateway) => {
+                    info!("The gateway on {} answers at {} baud", path, rate);
+                    return Ok(gateway);
+                }
+                // an echo or a banner is not a matter of the rate
+                Err(e) if matches!(e.downcast_ref(), Some(GatewayError::NotAGateway(_))) => {
+                    return Err(e).with_context(|| format!("Probing {} at {} baud", path, rate))
+                }
+                Err(e) => debug!("No answer at {} baud: {:#}", rate, e),
+            }
+        }
+        Err(anyhow!(
+            "The gateway on {} did not answer at any of {:?} baud",
+            path,
+            PROBE_BAUDRATES
+        ))
+    }
+
+    /// Drives an already opened port, its timeout is kept for writes
+    pub fn from_port(port: Box<dyn SerialPort>) -> GatewayDriver {
+        GatewayDriver {
+            port,
+            timeout: Duration::from_millis(100),
+            max_frame: MAX_FRAME,
+            received: Vec::new(),
+        }
+    }
+
+    pub fn write(&mut self, packet: HostPacket) -> Result<()> {
+        self.write_burst(&[packet])?;
+        sleep(Duration::from_millis(500));
+        Ok(())
+    }
+
+    /// Sends the packets back to back in one write, without the pause [`Self::write`] leaves
+    /// the gateway after each
+    pub fn write_burst(&mut self, packets: &[HostPacket]) -> Result<()> {
+        let mut encoded = Vec::with_capacity(packets.len() * MAX_FRAME);
+        for packet in packets {
+            let mut frame = [0u8; MAX_FRAME];
+            let len = self.encode(packet, &mut frame)?;
+            encoded.extend_from_slice(&frame[..len]);
+        }
+
+        self.port
+            .write_all(&encoded)
+            .with_context(|| format!("failed to send {:0X?}", encoded))?;
+        metrics::add("gateway.frames_sent", packets.len() as u64);
+        Ok(())
+    }
+
+    /// Frames `packet` into `frame`, returns the length of the frame
+    fn encode(&self, packet: &HostPacket, frame: &mut [u8; MAX_FRAME]) -> Result<usize> {
+        let mut buffer = [0u8; MAX_FRAME];
+        let to_encode = postcard::to_slice(packet, &mut buffer).map_err(GatewayError::SerDe)?;
+        let len = codec::encode(to_encode, frame)?;
+        if len > self.max_frame {
+            return Err(GatewayError::FrameTooLong {
+                len,
+                max: self.max_frame,
+            }
+            .into());
+        }
+        Ok(len)
+    }
+
+    /// Fails with [`GatewayError::FrameTooLong`] when `packet` would not fit the gateway's frame
+    /// buffer, to refuse a transfer up front rather than on its largest block
+    pub fn check_fits(&self, packet: &HostPacket) -> Result<()> {
+        self.encode(packet, &mut [0u8; MAX_FRAME]).map(|_| ())
+    }
+
+    /// Longest frame, terminator included, the driver sends
+    pub fn max_frame(&self) -> usize {
+        self.max_frame
+    }
+
+    /// Limits the frames sent to what the gateway buffers, at most [`MAX_FRAME`]. The schema
+    /// has no packet to ask the gateway for its limit yet, so it has to be configured.
+    pub fn set_max_frame(&mut self, max: usize) -> Result<()> {
+        if !(2..=MAX_FRAME).contains(&max) {
+            return Err(anyhow!(
+                "The maximum frame length must be between 2 and {}, got {}",
+                MAX_FRAME,
+                max
+            ));
+        }
+        self.max_frame = max;
+        Ok(())
+    }
+
+    pub fn read_with_timeout(&mut self, timeout: Duration) -> Result<GatewayPacket> {
+        let frame = self.read_frame(timeout)?;
+        decode(&frame)
+    }
+
+    /// Waits for the next frame and returns it unescaped but not decoded
+    fn read_frame(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        let start = Instant::now();
+        let mut decoder = Decoder::new();
+
+        // reads block in the kernel for whatever is left of the timeout, the port timeout
+        // also bounds writes so it is put back afterwards
+        let write_timeout = self.port.timeout();
+        let received = self.receive(&mut decoder, start + timeout);
+        self.port
+            .set_timeout(write_timeout)
+            .context("Failed to restore the port timeout")?;
+        match received {
+            Err(_) if is_text(decoder.frame()) => {
+                return Err(GatewayError::NotAGateway(format!(
+                    "it sent the text {:?} rather than frames, the port may belong to another \
+                     device",
+                    String::from_utf8_lossy(decoder.frame()).trim()
+                ))
+                .into());
+            }
+            Err(e) if GatewayError::is_timeout(&e) => {
+                metrics::increment("gateway.read_timeouts");
+                let hint = match decoder.frame() {
+                    [] => "nothing was received, check the port and that the gateway is powered"
+                        .to_owned(),
+                    partial => format!("received the unterminated frame {:02X?}", partial),
+                };
+                return Err(e).with_context(|| {
+                    format!("No answer within {} ms, {}", timeout.as_millis(), hint)
+                });
+            }
+            Err(e) => return Err(e),
+            Ok(()) => {}
+        }
+        metrics::increment("gateway.frames_received");
+        metrics::observe("gateway.response_ms", start.elapsed().as_secs_f64() * 1e3);
+        Ok(decoder.frame().to_vec())
+    }
+
+    /// Feeds the decoder until it holds a whole frame or the deadline passes, bytes read past
+    /// the end of the frame are kept for the next one
+    fn receive(&mut self, decoder: &mut Decoder, deadline: Instant) -> Result<()> {
+        loop {
+            if !self.received.is_empty() {
+                match decoder.push_slice(&self.received) {
+                    Ok(Some(taken)) => {
+                        self.received.drain(..taken);
+                        return Ok(());
+                    }
+                    Ok(None) => self.received.clear(),
+                    Err(e) => {
+                        // frames after the malformed one are kept, the terminator is the
+                        // first one received since the decoder stops at it
+                        match self.received.iter().position(|b| *b == TERMINATOR) {
+                            Some(end) => drop(self.received.drain(..=end)),
+                            None => self.received.clear(),
+                        }
+                        metrics::increment("gateway.malformed_frames");
+                        return Err(e).with_context(|| {
+                            format!(
+                                "Malformed frame from the gateway after {:02X?}",
+                                decoder.frame()
+                            )
+                        });
+                    }
+                }
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(GatewayError::ReadTimeout(ErrorKind::TimedOut.into()).into());
+            }
+            self.port.set_timeout(remaining)?;
+            let mut chunk = [0u8; READ_CHUNK];
+            match self.port.read(&mut chunk) {
+                Ok(0) => return Err(anyhow!("The gateway port was closed")),
+                Ok(len) => self.received.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::TimedOut => {
+                    return Err(GatewayError::ReadTimeout(e).into())
+                }
+                Err(e) => return Err(e).context("Failed to read from the gateway"),
+            }
+        }
+    }
+
+    pub fn read(&mut self) -> Result<GatewayPacket> {
+        self.read_with_timeout(self.timeout)
+    }
+
+    /// Checks the gateway answers. A reply other than the ping response is looked into, a port
+    /// sending requests back is reported as [`GatewayError::NotAGateway`] rather than taken
+    /// for a gateway. A loopback answers the ping itself when the response encodes like the
+    /// request, it is caught by the first other request then.
+    pub fn ping(&mut self) -> Result<Duration> {
+        let start = Instant::now();
+        self.write(HostPacket::PingRequest)
+            .context("write failed")?;
+
+        let frame = self.read_frame(self.timeout).context("read failed")?;
+        if frame != payload(&GatewayPacket::PingResponse)?
+            && frame == payload(&HostPacket::PingRequest)?
+        {
+            return Err(echo_error());
+        }
+        let error = match decode(&frame) {
+            Ok(GatewayPacket::PingResponse) => return Ok(Instant::now() - start),
+            Ok(p) => GatewayError::unexpected("PingResponse", &p).into(),
+            Err(e) => e,
+        };
+        if self.echoes(HostPacket::OtaGetStatus)? {
+            return Err(echo_error());
+        }
+        Err(error).context("read failed")
+    }
+
+    /// Whether the port answers `packet` with the packet itself, it should only be a query.
+    /// The answer is waited for and taken off the line either way, so that it is not read as
+    /// the response to the next request.
+    fn echoes(&mut self, packet: HostPacket) -> Result<bool> {
+        let request = payload(&packet)?;
+        self.write(packet)?;
+        let echoed = match self.read_frame(ECHO_TIMEOUT) {
+            Ok(frame) => frame == request,
+            Err(e) if GatewayError::is_timeout(&e) => false,
+            Err(e) => return Err(e),
+        };
+        self.received.clear();
+        Ok(echoed)
+    }
+
+    /// Requests a single moisture reading from the sensor node
+    pub fn soil_sensor(&mut self, destination_address: usize) -> Result<[u16; 4]> {
+        self.write(HostPacket::SoilSensor(SoilSensorRequest {
+            destination_address,
+        }))?;
+        match self
+            .read_with_timeout(Duration::from_secs(1))
+            .context("Response timeout")?
+        {
+            GatewayPacket::SoilSensorMoisture(s) => Ok(s),
+            p => Err(GatewayError::unexpected("SoilSensorMoisture", &p)).context(
+                "The gateway answered the sensor request with something else, \
+                 a response to an earlier request may have arrived late",
+            ),
+        }
+    }
+}
+
+/// Serializes a packet the way it goes into a frame, before escaping
+fn payload(packet: &impl serde::Serialize) -> Result<Vec<u8>> {
+    let mut buffer = [0u8; MAX_FRAME];
+    Ok(postcard::to_slice(packet, &mut buffer)
+        .map_err(GatewayError::SerDe)?
+        .to_vec())
+}
+
+fn decode(frame: &[u8]) -> Result<GatewayPacket> {
+    postcard::from_bytes::<GatewayPacket>(frame)
+        .map_err(GatewayError::SerDe)
+        .with_context(|| {
+            format!(
+                "Failed to decode the frame {:02X?} as a GatewayPacket, the gateway firmware \
+                 may be built from a different gateway-host-schema, compare with the schema \
+                 subcommand",
+                frame
+            )
+        })
+}
+
+fn echo_error() -> anyhow::Error {
+    GatewayError::NotAGateway(
+        "it sent our own request back, the port looks like a loopback or a device echoing its \
+         input"
+            .to_owned(),
+    )
+    .into()
+}
+
+/// Whether bytes that never made a frame read like a console banner or a modem's replies,
+/// going by a whole line or a sample longer than a frame cut short by noise would likely be
+fn is_text(received: &[u8]) -> bool {
+    let sample = received.ends_with(b"\n") || received.len() >= 16;
+    sample
+        && received.iter().filter(|b| b.is_ascii_graphic()).count() >= 2
+        && received
+            .iter()
+            .all(|b| b.is_ascii_graphic() || b.is_ascii_whitespace())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baudrates_parse_from_the_command_line_and_config() {
+        assert_eq!("auto".parse::<Baudrate>().unwrap(), Baudrate::Auto);
+        assert_eq!("9600".parse::<Baudrate>().unwrap(), Baudrate::Fixed(9600));
+        assert!("fast".parse::<Baudrate>().is_err());
+        assert!("-1".parse::<Baudrate>().is_err());
+        let parse = |json: &str| serde_json::from_str::<Baudrate>(json);
+        assert_eq!(parse("115200").unwrap(), Baudrate::Fixed(115200));
+        assert_eq!(parse("\"921600\"").unwrap(), Baudrate::Fixed(921600));
+        assert_eq!(parse("\"auto\"").unwrap(), Baudrate::Auto);
+        assert!(parse("\"fast\"").is_err());
+    }
+
+    #[test]
+    fn text_is_told_from_frames() {
+        assert!(is_text(b"\r\nERROR\r\n"));
+        assert!(is_text(b"booting firmware v1"));
+        // a short frame can be all printable bytes
+        assert!(!is_text(b"ab"));
+        assert!(!is_text(&[0x00, 0x01, 0x02, 0x0a]));
+        assert!(!is_text(b""));
+    }
+}
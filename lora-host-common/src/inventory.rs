@@ -0,0 +1,212 @@
+use crate::addressbook::{AddressBook, NodeRef};
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+pub const DEFAULT_PATH: &str = "inventory.json";
+
+/// What is known about a node, `name` and `hardware_rev` are kept by hand
+#[derive(Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeRecord {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hardware_rev: Option<String>,
+    /// Logical groups like `greenhouse` or `hw-rev2`, targeted as `tag:greenhouse`
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub tags: BTreeSet<String>,
+    /// Address the update is retried at when the node does not answer at its own, e.g. its
+    /// bootloader's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback_address: Option<usize>,
+    /// SHA-256 of the image the node was last updated with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware_sha256: Option<String>,
+    /// RFC 3339 time the node last answered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_contact: Option<String>,
+}
+
+/// Known nodes by address, kept in a JSON file the tools update as they talk to nodes
+pub struct Inventory {
+    path: PathBuf,
+    pub nodes: BTreeMap<usize, NodeRecord>,
+}
+
+impl Inventory {
+    /// Opens the inventory, an absent file is an empty inventory
+    pub fn load(path: &Path) -> Result<Inventory> {
+        let nodes = match std::fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)
+                .with_context(|| format!("Failed to parse the inventory {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read the inventory {}", path.display()))
+            }
+        };
+        Ok(Inventory {
+            path: path.to_owned(),
+            nodes,
+        })
+    }
+
+    /// An inventory without nodes, saving it replaces the file at `path`
+    pub fn empty(path: &Path) -> Inventory {
+        Inventory {
+            path: path.to_owned(),
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        std::fs::write(&self.path, serde_json::to_string_pretty(&self.nodes)?)
+            .with_context(|| format!("Failed to write the inventory {}", self.path.display()))
+    }
+
+    pub fn node_mut(&mut self, address: usize) -> &mut NodeRecord {
+        self.nodes.entry(address).or_default()
+    }
+
+    pub fn record_contact(&mut self, address: usize) {
+        self.node_mut(address).last_contact = Some(Utc::now().to_rfc3339());
+    }
+
+    /// Addresses a target stands for, names are looked up in `book`, a tag no node carries
+    /// is an error
+    pub fn resolve(&self, target: &Target, book: &AddressBook) -> Result<Vec<usize>> {
+        match target {
+            Target::Node(address) => Ok(vec![*address]),
+            Target::Name(name) => Ok(vec![book.address(&NodeRef::Name(name.clone()))?]),
+            Target::Tag(tag) => {
+                let nodes = self
+                    .nodes
+                    .iter()
+                    .filter(|(_, node)| node.tags.contains(tag))
+                    .map(|(address, _)| *address)
+                    .collect::<Vec<usize>>();
+                match nodes.is_empty() {
+                    true => Err(anyhow!(
+                        "No node in {} is tagged {}",
+                        self.path.display(),
+                        tag
+                    )),
+                    false => Ok(nodes),
+                }
+            }
+        }
+    }
+
+    pub fn fallback_address(&self, address: usize) -> Option<usize> {
+        self.nodes.get(&address).and_then(|n| n.fallback_address)
+    }
+
+    /// The node confirmed an image with this checksum
+    pub fn record_update(&mut self, address: usize, sha256: &[u8; 32]) {
+        self.record_contact(address);
+        self.node_mut(address).firmware_sha256 = Some(hex(sha256));
+    }
+}
+
+/// Lowercase hex, how checksums are shown and stored
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A node address, its name in the address book or `tag:<name>` for every node carrying
+/// the tag
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "RawTarget")]
+pub enum Target {
+    Node(usize),
+    Name(String),
+    Tag(String),
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix("tag:") {
+            Some("") => Err(anyhow!("empty tag in {:?}", s)),
+            Some(tag) => Ok(Target::Tag(tag.to_owned())),
+            None => match s.parse::<NodeRef>() {
+                Ok(NodeRef::Address(address)) => Ok(Target::Node(address)),
+                Ok(NodeRef::Name(name)) => Ok(Target::Name(name)),
+                Err(_) => Err(anyhow!(
+                    "expected a node address, name or tag:<name>, got {:?}",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Node(address) => write!(f, "{}", address),
+            Target::Name(name) => f.write_str(name),
+            Target::Tag(tag) => write!(f, "tag:{}", tag),
+        }
+    }
+}
+
+/// Plain numbers in YAML and JSON plans are addresses
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawTarget {
+    Address(usize),
+    Text(String),
+}
+
+impl TryFrom<RawTarget> for Target {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawTarget) -> Result<Self> {
+        match raw {
+            RawTarget::Address(address) => Ok(Target::Node(address)),
+            RawTarget::Text(text) => text.parse(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_parse_from_the_command_line_and_plans() {
+        assert_eq!("7".parse::<Target>().unwrap(), Target::Node(7));
+        assert_eq!(
+            "tag:greenhouse".parse::<Target>().unwrap(),
+            Target::Tag("greenhouse".to_owned())
+        );
+        assert_eq!(
+            "pump".parse::<Target>().unwrap(),
+            Target::Name("pump".to_owned())
+        );
+        for invalid in ["tag:", "a b", "a:b", ""] {
+            assert!(invalid.parse::<Target>().is_err(), "{:?}", invalid);
+        }
+        let targets: Vec<Target> = serde_json::from_str(r#"[3, "4", "tag:field"]"#).unwrap();
+        assert_eq!(
+            targets,
+            [
+                Target::Node(3),
+                Target::Node(4),
+                Target::Tag("field".to_owned())
+            ]
+        );
+        for target in targets {
+            assert_eq!(target.to_string().parse::<Target>().unwrap(), target);
+        }
+    }
+}
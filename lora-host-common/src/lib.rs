@@ -0,0 +1,15 @@
+//! Host side of the gateway link shared by module-updater and soil-sensor-reader
+
+pub mod addressbook;
+pub mod codec;
+pub mod gateway;
+pub mod inventory;
+pub mod lock;
+pub mod logging;
+pub mod metrics;
+pub mod ota;
+pub mod retry;
+pub mod schema;
+pub mod secret;
+pub mod selftest;
+pub mod term;
@@ -0,0 +1,81 @@
+//! Lock files keeping two processes from running OTA sessions to the same node, or two
+//! monitors on the same data
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{Seek, Write},
+    path::Path,
+};
+
+/// An advisory lock on a file, the OS releases it when the holder exits however it exits so
+/// there are no stale locks to clean up. The file itself is left in place: removing it would
+/// let a process that opened it before the removal lock the orphaned file while another
+/// creates and locks a new one.
+pub struct FileLock {
+    file: File,
+}
+
+impl FileLock {
+    /// Takes the lock, None while another process holds it
+    pub fn try_acquire(path: &Path) -> Result<Option<FileLock>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open the lock {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(FileLock { file })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => {
+                Err(e).with_context(|| format!("Failed to lock {}", path.display()))
+            }
+        }
+    }
+
+    /// Whether another process holds the lock, checked by taking it and letting it go
+    pub fn is_held(path: &Path) -> Result<bool> {
+        Ok(FileLock::try_acquire(path)?.is_none())
+    }
+
+    /// Records the process holding the lock in the file, for the error of the next one
+    fn write_owner(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        writeln!(self.file, "{}", std::process::id())?;
+        Ok(())
+    }
+}
+
+/// Held for the duration of an OTA session
+pub struct NodeLock {
+    _lock: FileLock,
+}
+
+impl NodeLock {
+    /// Fails with the owning process when another session to the node is running
+    pub fn acquire(address: usize) -> Result<NodeLock> {
+        let path = std::env::temp_dir().join(format!("lora-ota-node-{}.lock", address));
+        match FileLock::try_acquire(&path)? {
+            Some(mut lock) => {
+                lock.write_owner()
+                    .with_context(|| format!("Failed to write the lock {}", path.display()))?;
+                Ok(NodeLock { _lock: lock })
+            }
+            None => Err(anyhow!(
+                "Node {} is already being updated{}",
+                address,
+                owner(&path).map_or(String::new(), |pid| format!(" by process {}", pid))
+            )),
+        }
+    }
+}
+
+/// The process a lock file names, unreadable where locks are mandatory
+fn owner(path: &Path) -> Option<u32> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|pid| pid.trim().parse().ok())
+}
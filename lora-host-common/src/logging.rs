@@ -0,0 +1,64 @@
+use crate::term::{self, ColorChoice};
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use std::{fs::OpenOptions, io::IsTerminal, path::PathBuf, sync::Mutex};
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per event
+    Json,
+}
+
+/// Logging flags shared by all the binaries, the level filter is taken from `RUST_LOG`
+#[derive(clap::Args)]
+pub struct LogArgs {
+    /// Format of the diagnostic log on stderr and in the log file
+    #[clap(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Also append the diagnostic log to this file
+    #[clap(long, global = true)]
+    pub log_file: Option<PathBuf>,
+
+    /// Color status lines and log levels
+    #[clap(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+}
+
+/// Installs the global subscriber, `RUST_LOG` defaults to `info`, and sets up [`term`]
+pub fn init(args: &LogArgs) -> Result<()> {
+    term::init(args.log_format, args.color);
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let file = args
+        .log_file
+        .as_ref()
+        .map(|path| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open the log file {}", path.display()))
+        })
+        .transpose()?
+        .map(Mutex::new);
+
+    let registry = tracing_subscriber::registry().with(filter);
+    match args.log_format {
+        LogFormat::Text => registry
+            .with(
+                fmt::layer()
+                    .with_ansi(args.color.enabled(std::io::stderr().is_terminal()))
+                    .with_writer(term::stderr),
+            )
+            .with(file.map(|f| fmt::layer().with_ansi(false).with_writer(f)))
+            .try_init(),
+        LogFormat::Json => registry
+            .with(fmt::layer().json().with_writer(std::io::stderr))
+            .with(file.map(|f| fmt::layer().json().with_writer(f)))
+            .try_init(),
+    }
+    .map_err(|e| anyhow!("Failed to set up logging: {}", e))
+}
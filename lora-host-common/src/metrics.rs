@@ -0,0 +1,149 @@
+//! Process wide counters, gauges and histograms shared by the driver and the tools,
+//! summarized in a report at the end of a run
+
+use std::{collections::BTreeMap, sync::Mutex};
+use tracing::info;
+
+enum Metric {
+    Counter(u64),
+    Gauge(f64),
+    Histogram(Summary),
+}
+
+/// Samples a histogram keeps for its percentiles, a monitor running for months observes
+/// millions
+const RESERVOIR: usize = 1024;
+
+/// Exact count, min and max of a histogram, percentiles from a uniform random sample of it
+struct Summary {
+    count: u64,
+    min: f64,
+    max: f64,
+    sample: Vec<f64>,
+}
+
+impl Summary {
+    fn new(value: f64) -> Self {
+        Summary {
+            count: 1,
+            min: value,
+            max: value,
+            sample: vec![value],
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        // reservoir sampling, every value so far is kept with the same probability
+        if self.sample.len() < RESERVOIR {
+            self.sample.push(value);
+        } else if let Some(slot) = self.sample.get_mut(fastrand::u64(..self.count) as usize) {
+            *slot = value;
+        }
+    }
+}
+
+static METRICS: Mutex<BTreeMap<&'static str, Metric>> = Mutex::new(BTreeMap::new());
+
+fn update(name: &'static str, f: impl FnOnce(Option<&mut Metric>) -> Option<Metric>) {
+    let mut metrics = METRICS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(metric) = f(metrics.get_mut(name)) {
+        metrics.insert(name, metric);
+    }
+}
+
+/// Adds `n` to a counter
+pub fn add(name: &'static str, n: u64) {
+    update(name, |metric| match metric {
+        Some(Metric::Counter(count)) => {
+            *count += n;
+            None
+        }
+        _ => Some(Metric::Counter(n)),
+    });
+}
+
+pub fn increment(name: &'static str) {
+    add(name, 1);
+}
+
+/// Sets a gauge to its latest value
+pub fn gauge(name: &'static str, value: f64) {
+    update(name, |_| Some(Metric::Gauge(value)));
+}
+
+/// Records one sample of a histogram
+pub fn observe(name: &'static str, value: f64) {
+    update(name, |metric| match metric {
+        Some(Metric::Histogram(summary)) => {
+            summary.observe(value);
+            None
+        }
+        _ => Some(Metric::Histogram(Summary::new(value))),
+    });
+}
+
+/// One line per metric, histograms as count, min, median, 95th percentile and max
+pub fn report() -> Vec<String> {
+    let metrics = METRICS.lock().unwrap_or_else(|e| e.into_inner());
+    metrics
+        .iter()
+        .map(|(name, metric)| match metric {
+            Metric::Counter(count) => format!("{} {}", name, count),
+            Metric::Gauge(value) => format!("{} {:.2}", name, value),
+            Metric::Histogram(summary) => {
+                let mut sorted = summary.sample.clone();
+                sorted.sort_by(f64::total_cmp);
+                let quantile = |q: f64| sorted[((sorted.len() - 1) as f64 * q).round() as usize];
+                format!(
+                    "{} count {} min {:.2} p50 {:.2} p95 {:.2} max {:.2}",
+                    name,
+                    summary.count,
+                    summary.min,
+                    quantile(0.5),
+                    quantile(0.95),
+                    summary.max
+                )
+            }
+        })
+        .collect()
+}
+
+/// Logs the report, nothing when no metric was recorded
+pub fn log_report() {
+    for line in report() {
+        info!(target: "metrics", "{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_stay_bounded() {
+        let mut summary = Summary::new(5000.0);
+        for i in 0..5000 {
+            summary.observe(i as f64);
+        }
+        assert_eq!(summary.count, 5001);
+        assert_eq!(summary.sample.len(), RESERVOIR);
+        assert_eq!((summary.min, summary.max), (0.0, 5000.0));
+    }
+
+    #[test]
+    fn report_summarizes_histograms() {
+        for value in [3.0, 1.0, 2.0] {
+            observe("test.histogram", value);
+        }
+        let report = report();
+        assert!(
+            report
+                .contains(&"test.histogram count 3 min 1.00 p50 2.00 p95 3.00 max 3.00".to_owned()),
+            "{:?}",
+            report
+        );
+    }
+}
@@ -0,0 +1,907 @@
+use crate::{
+    gateway::{GatewayDriver, GatewayError},
+    lock::NodeLock,
+    metrics,
+    retry::RetryPolicy,
+};
+use anyhow::{anyhow, Context, Result};
+use gateway_host_schema::*;
+use memmap2::Mmap;
+use ring::digest;
+use std::{
+    borrow::Cow,
+    fmt,
+    fs::File,
+    io::Write,
+    ops::Range,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::sleep,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+pub const BLOCK_SIZE: usize = 64;
+/// How often the status is polled while [`Options::paused`] is set
+pub const PAUSED_POLL: Duration = Duration::from_secs(5);
+/// Value of erased flash, what images are padded with
+pub const ERASED: u8 = 0xff;
+
+/// Transfer parameters, the defaults are what the gateway firmware has been tested with
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Payload bytes per block, at most [`BLOCK_SIZE`] which the `OtaData` packet holds
+    pub block_size: usize,
+    /// How long the node may take to prepare for or abort an update
+    pub init_timeout: Duration,
+    /// How long to wait for the gateway to answer a block or status request
+    pub response_timeout: Duration,
+    /// How many blocks past the last acknowledged one may be in flight before new blocks
+    /// are held back
+    pub window: u16,
+    /// Pause after each status from the gateway, gives the radio time to settle
+    pub status_pause: Duration,
+    /// Consecutive new blocks answered without the acknowledgement advancing (or not answered
+    /// at all) after which the update is aborted, 0 never gives up. Retransmissions, pauses
+    /// and waiting for the node to verify the image do not count.
+    pub max_stalls: u32,
+    /// Wall-clock budget of the whole update, it is aborted once exceeded
+    pub max_duration: Option<Duration>,
+    /// While set no blocks are sent, the gateway is asked for the status every
+    /// [`PAUSED_POLL`] instead to keep the session alive, clear it to resume
+    pub paused: Arc<AtomicBool>,
+    /// How the status, abort and init requests before the transfer are repeated when the
+    /// gateway does not answer, the node is given up on after the last init timeout
+    pub retry: RetryPolicy,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            block_size: BLOCK_SIZE,
+            init_timeout: Duration::from_secs(30),
+            response_timeout: Duration::from_secs(3),
+            window: 12,
+            status_pause: Duration::from_millis(150),
+            max_stalls: 0,
+            max_duration: None,
+            paused: Arc::new(AtomicBool::new(false)),
+            retry: RetryPolicy {
+                retries: 2,
+                backoff: Duration::from_millis(500),
+            },
+        }
+    }
+}
+
+/// The node did not acknowledge the start of the update within [`Options::init_timeout`],
+/// find it in the error chain with `downcast_ref`
+#[derive(Error, Debug)]
+#[error("node {0} did not acknowledge the start of the update in time")]
+pub struct InitTimeout(pub usize);
+
+/// Snapshot of a running transfer, reported after every gateway response
+#[derive(Clone, Debug)]
+pub struct Progress {
+    pub block_count: usize,
+    /// Index of the last block the node acknowledged, `block_count` once it is done
+    pub acked: usize,
+    /// Blocks sent so far, including retransmissions
+    pub sent: usize,
+    pub retransmitted: usize,
+    pub elapsed: Duration,
+    pub done: bool,
+}
+
+impl Progress {
+    /// Blocks the node has, the acknowledged index and those before it
+    pub fn acked_blocks(&self) -> usize {
+        match self.done {
+            true => self.block_count,
+            false => self.acked.saturating_add(1).min(self.block_count),
+        }
+    }
+
+    pub fn percent(&self) -> f64 {
+        100.0 * self.acked_blocks() as f64 / self.block_count.max(1) as f64
+    }
+
+    /// Share of the sent blocks that were retransmissions
+    pub fn retransmit_rate(&self) -> f64 {
+        self.retransmitted as f64 / self.sent.max(1) as f64
+    }
+
+    /// Remaining time at the acknowledgement rate seen so far
+    pub fn eta(&self) -> Option<Duration> {
+        if self.done {
+            return Some(Duration::ZERO);
+        }
+        let acked = self.acked_blocks();
+        (acked > 0).then(|| {
+            self.elapsed
+                .mul_f64(self.block_count.saturating_sub(acked) as f64 / acked as f64)
+        })
+    }
+}
+
+/// Steps of an update, [`update`] reports all but `Connect` which is up to the caller
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    /// Opening the port and pinging the gateway
+    Connect,
+    /// Clearing an update left in progress and waiting for the node to accept the new one
+    Init,
+    /// Sending blocks until the node has all of them
+    Transfer,
+    /// Waiting for the node to confirm the image checksum
+    Verify,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Connect => "connect",
+            Phase::Init => "init",
+            Phase::Transfer => "transfer",
+            Phase::Verify => "verify",
+        })
+    }
+}
+
+/// Hooks into a running transfer, e.g. to render a UI, all methods default to doing nothing
+pub trait Observer {
+    /// A block went out, `retransmission` when the node reported it missing before
+    fn on_block_sent(&mut self, _index: u16, _retransmission: bool) {}
+    /// The gateway answered with the node's acknowledgement status
+    fn on_ack(&mut self, _progress: &Progress) {}
+    /// The node reported a block missing, it is queued to be sent again
+    fn on_retransmit(&mut self, _index: u16) {}
+    /// The node confirmed the whole image
+    fn on_complete(&mut self, _progress: &Progress) {}
+    /// The update moved on to `phase`
+    fn on_phase(&mut self, _phase: Phase) {}
+}
+
+/// No observer
+impl Observer for () {}
+
+impl<T: Observer + ?Sized> Observer for &mut T {
+    fn on_block_sent(&mut self, index: u16, retransmission: bool) {
+        (**self).on_block_sent(index, retransmission)
+    }
+    fn on_ack(&mut self, progress: &Progress) {
+        (**self).on_ack(progress)
+    }
+    fn on_retransmit(&mut self, index: u16) {
+        (**self).on_retransmit(index)
+    }
+    fn on_complete(&mut self, progress: &Progress) {
+        (**self).on_complete(progress)
+    }
+    fn on_phase(&mut self, phase: Phase) {
+        (**self).on_phase(phase)
+    }
+}
+
+impl<T: Observer + ?Sized> Observer for Box<T> {
+    fn on_block_sent(&mut self, index: u16, retransmission: bool) {
+        (**self).on_block_sent(index, retransmission)
+    }
+    fn on_ack(&mut self, progress: &Progress) {
+        (**self).on_ack(progress)
+    }
+    fn on_retransmit(&mut self, index: u16) {
+        (**self).on_retransmit(index)
+    }
+    fn on_complete(&mut self, progress: &Progress) {
+        (**self).on_complete(progress)
+    }
+    fn on_phase(&mut self, phase: Phase) {
+        (**self).on_phase(phase)
+    }
+}
+
+impl<T: Observer> Observer for Option<T> {
+    fn on_block_sent(&mut self, index: u16, retransmission: bool) {
+        if let Some(o) = self {
+            o.on_block_sent(index, retransmission)
+        }
+    }
+    fn on_ack(&mut self, progress: &Progress) {
+        if let Some(o) = self {
+            o.on_ack(progress)
+        }
+    }
+    fn on_retransmit(&mut self, index: u16) {
+        if let Some(o) = self {
+            o.on_retransmit(index)
+        }
+    }
+    fn on_complete(&mut self, progress: &Progress) {
+        if let Some(o) = self {
+            o.on_complete(progress)
+        }
+    }
+    fn on_phase(&mut self, phase: Phase) {
+        if let Some(o) = self {
+            o.on_phase(phase)
+        }
+    }
+}
+
+/// Notifies both observers, nest the tuples for more
+impl<A: Observer, B: Observer> Observer for (A, B) {
+    fn on_block_sent(&mut self, index: u16, retransmission: bool) {
+        self.0.on_block_sent(index, retransmission);
+        self.1.on_block_sent(index, retransmission);
+    }
+    fn on_ack(&mut self, progress: &Progress) {
+        self.0.on_ack(progress);
+        self.1.on_ack(progress);
+    }
+    fn on_retransmit(&mut self, index: u16) {
+        self.0.on_retransmit(index);
+        self.1.on_retransmit(index);
+    }
+    fn on_complete(&mut self, progress: &Progress) {
+        self.0.on_complete(progress);
+        self.1.on_complete(progress);
+    }
+    fn on_phase(&mut self, phase: Phase) {
+        self.0.on_phase(phase);
+        self.1.on_phase(phase);
+    }
+}
+
+/// Diagnostic file with a `time,txed,acked` row per acknowledgement
+pub struct CsvLog {
+    file: File,
+    failed: bool,
+}
+
+impl CsvLog {
+    pub fn create(path: &Path) -> Result<CsvLog> {
+        let mut file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        file.write_all("time,txed,acked\n".as_bytes())?;
+        Ok(CsvLog {
+            file,
+            failed: false,
+        })
+    }
+
+    /// A failing diagnostic file is reported once and does not stop the transfer
+    fn row(&mut self, progress: &Progress) {
+        if self.failed {
+            return;
+        }
+        let row = format!(
+            "{},{},{}\n",
+            progress.elapsed.as_secs(),
+            progress.sent,
+            progress.acked
+        );
+        if let Err(e) = self.file.write_all(row.as_bytes()) {
+            warn!("Failed to write the diagnostic file: {}", e);
+            self.failed = true;
+        }
+    }
+}
+
+impl Observer for CsvLog {
+    fn on_ack(&mut self, progress: &Progress) {
+        self.row(progress);
+    }
+    fn on_complete(&mut self, progress: &Progress) {
+        self.row(progress);
+    }
+}
+
+/// How often [`JsonLog`] writes a snapshot of every block's state
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What the host knows about a block, as shown in [`JsonLog`] snapshots
+#[derive(Clone, Copy, PartialEq)]
+enum BlockState {
+    /// `.` not sent yet
+    Unsent,
+    /// `p` sent, not yet covered by the acknowledgement
+    Pending,
+    /// `r` reported missing by the node and queued or sent again
+    Retransmitting,
+    /// `a` acknowledged
+    Acked,
+}
+
+/// Diagnostic file with a JSON object per line, one per acknowledgement and a `blocks`
+/// snapshot every [`SNAPSHOT_INTERVAL`] and at the end, one character per block as in
+/// `aaaaprr.....`, to see which parts of the image were lost
+pub struct JsonLog {
+    file: File,
+    failed: bool,
+    blocks: Vec<BlockState>,
+    last_snapshot: Option<Duration>,
+}
+
+impl JsonLog {
+    pub fn create(path: &Path) -> Result<JsonLog> {
+        Ok(JsonLog {
+            file: File::create(path)
+                .with_context(|| format!("Failed to create {}", path.display()))?,
+            failed: false,
+            blocks: Vec::new(),
+            last_snapshot: None,
+        })
+    }
+
+    fn block(&mut self, index: u16) -> &mut BlockState {
+        let index = index as usize;
+        if index >= self.blocks.len() {
+            self.blocks.resize(index + 1, BlockState::Unsent);
+        }
+        &mut self.blocks[index]
+    }
+
+    /// A failing diagnostic file is reported once and does not stop the transfer
+    fn line(&mut self, value: serde_json::Value) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = writeln!(self.file, "{}", value) {
+            warn!("Failed to write the diagnostic file: {}", e);
+            self.failed = true;
+        }
+    }
+
+    fn record(&mut self, progress: &Progress) {
+        if self.blocks.len() < progress.block_count {
+            self.blocks.resize(progress.block_count, BlockState::Unsent);
+        }
+        let acked = progress.acked_blocks().min(self.blocks.len());
+        for block in &mut self.blocks[..acked] {
+            *block = BlockState::Acked;
+        }
+        self.line(serde_json::json!({
+            "time": progress.elapsed.as_secs_f64(),
+            "sent": progress.sent,
+            "acked": progress.acked,
+            "retransmitted": progress.retransmitted,
+        }));
+
+        let due = self
+            .last_snapshot
+            .is_none_or(|last| progress.elapsed >= last + SNAPSHOT_INTERVAL);
+        if due || progress.done {
+            self.last_snapshot = Some(progress.elapsed);
+            let blocks = self
+                .blocks
+                .iter()
+                .map(|b| match b {
+                    BlockState::Unsent => '.',
+                    BlockState::Pending => 'p',
+                    BlockState::Retransmitting => 'r',
+                    BlockState::Acked => 'a',
+                })
+                .collect::<String>();
+            self.line(serde_json::json!({
+                "time": progress.elapsed.as_secs_f64(),
+                "blocks": blocks,
+            }));
+        }
+    }
+}
+
+impl Observer for JsonLog {
+    fn on_block_sent(&mut self, index: u16, _retransmission: bool) {
+        let block = self.block(index);
+        if *block == BlockState::Unsent {
+            *block = BlockState::Pending;
+        }
+    }
+    fn on_ack(&mut self, progress: &Progress) {
+        self.record(progress);
+    }
+    fn on_retransmit(&mut self, index: u16) {
+        *self.block(index) = BlockState::Retransmitting;
+    }
+    fn on_complete(&mut self, progress: &Progress) {
+        self.record(progress);
+    }
+}
+
+/// Creates a [`JsonLog`] for `.jsonl` paths and a [`CsvLog`] otherwise
+pub fn debug_log(path: &Path) -> Result<Box<dyn Observer>> {
+    Ok(match path.extension().is_some_and(|e| e == "jsonl") {
+        true => Box::new(JsonLog::create(path)?),
+        false => Box::new(CsvLog::create(path)?),
+    })
+}
+
+/// Maps the firmware image instead of reading it, so a multi-megabyte image is paged in
+/// as blocks are sent rather than held in memory. The file must not be truncated while the
+/// map is in use.
+pub fn map_binary(path: &Path) -> Result<Mmap> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    // Safety: the map is only read. An image rewritten in place during the transfer makes the
+    // node reject the checksum, but one truncated under the map raises SIGBUS on the next
+    // block read past its new end and kills the process. Nothing here writes images, builds
+    // replacing one are expected to write a new file and rename it over the old one, which
+    // leaves the mapped file intact.
+    unsafe { Mmap::map(&file) }.with_context(|| format!("Failed to map {}", path.display()))
+}
+
+/// Sends a request and waits for the answer, both are repeated while the gateway times out
+fn request(
+    gateway: &mut GatewayDriver,
+    packet: impl Fn() -> HostPacket,
+    timeout: Duration,
+    retry: &RetryPolicy,
+) -> Result<GatewayPacket> {
+    let (response, attempts) = retry.run_if(GatewayError::is_timeout, |attempt| {
+        if attempt > 1 {
+            debug!("Repeating the request, attempt {}", attempt);
+        }
+        gateway.write(packet())?;
+        gateway.read_with_timeout(timeout)
+    });
+    metrics::add("ota.request_retries", attempts as u64 - 1);
+    response
+}
+
+/// Checks that the image starts with a plausible Cortex-M vector table, an initial stack
+/// pointer in `ram` and a Thumb reset handler in `flash`, to refuse files that are not
+/// firmware at all before a long transfer
+pub fn check_vector_table(binary: &[u8], ram: &Range<u32>, flash: &Range<u32>) -> Result<()> {
+    if binary.starts_with(b"\x7fELF") {
+        return Err(anyhow!(
+            "the image is an ELF file, convert it to a raw binary with objcopy -O binary"
+        ));
+    }
+    let word = |i: usize| {
+        binary
+            .get(i * 4..i * 4 + 4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+    };
+    let (Some(stack_pointer), Some(reset)) = (word(0), word(1)) else {
+        return Err(anyhow!(
+            "the image is {}B, too small to hold a vector table",
+            binary.len()
+        ));
+    };
+    // the stack grows down, so starting at the very end of RAM is fine
+    if stack_pointer <= ram.start || stack_pointer > ram.end || stack_pointer % 4 != 0 {
+        return Err(anyhow!(
+            "the initial stack pointer {:#010x} is not in RAM {:#010x}..{:#010x}, \
+             this does not look like a firmware image",
+            stack_pointer,
+            ram.start,
+            ram.end
+        ));
+    }
+    if reset & 1 == 0 || !flash.contains(&(reset & !1)) {
+        return Err(anyhow!(
+            "the reset vector {:#010x} is not a Thumb address in flash {:#010x}..{:#010x}, \
+             this does not look like a firmware image",
+            reset,
+            flash.start,
+            flash.end
+        ));
+    }
+    Ok(())
+}
+
+/// Pads the image with erased flash bytes to at least `pad_to` and then to a multiple of
+/// `align`, as the target bootloader's page or sector layout may need. Only copies the
+/// image when padding is needed.
+pub fn pad(binary: &[u8], pad_to: Option<usize>, align: Option<usize>) -> Result<Cow<'_, [u8]>> {
+    let mut len = binary.len();
+    if let Some(pad_to) = pad_to {
+        if pad_to < len {
+            return Err(anyhow!(
+                "the image is {}B, larger than the {}B to pad it to",
+                len,
+                pad_to
+            ));
+        }
+        len = pad_to;
+    }
+    if let Some(align) = align {
+        if align == 0 {
+            return Err(anyhow!("the alignment must not be 0"));
+        }
+        len = len.div_ceil(align) * align;
+    }
+    if len == binary.len() {
+        return Ok(Cow::Borrowed(binary));
+    }
+
+    let mut padded = Vec::with_capacity(len);
+    padded.extend_from_slice(binary);
+    padded.resize(len, ERASED);
+    Ok(Cow::Owned(padded))
+}
+
+/// SHA-256 of the image as the node verifies it, computed in chunks so a mapped image is
+/// streamed through
+pub fn checksum(binary: &[u8]) -> [u8; 32] {
+    let mut c = digest::Context::new(&digest::SHA256);
+    let mut ret = [0u8; 32];
+    for chunk in binary.chunks(1 << 16) {
+        c.update(chunk);
+    }
+    ret.copy_from_slice(c.finish().as_ref());
+    ret
+}
+
+/// Transfers `binary` to the node through a connected gateway, aborting an update left in
+/// progress first. The `observer` is told about every block and acknowledgement. Another
+/// process already updating the node is an error, see [`NodeLock`].
+pub fn update(
+    gateway: &mut GatewayDriver,
+    destination_address: usize,
+    binary: &[u8],
+    options: &Options,
+    observer: &mut dyn Observer,
+) -> Result<()> {
+    let start = Instant::now();
+    let _lock = NodeLock::acquire(destination_address)?;
+    let binary_checksum = checksum(binary);
+    let block_size = options.block_size;
+    if !(1..=BLOCK_SIZE).contains(&block_size) {
+        return Err(anyhow!(
+            "block size must be between 1 and {}, got {}",
+            BLOCK_SIZE,
+            block_size
+        ));
+    }
+    let index_count = binary.len().div_ceil(block_size);
+    if index_count > u16::MAX as usize {
+        return Err(anyhow!(
+            "{}B do not fit in {} blocks of size {}",
+            binary.len(),
+            u16::MAX,
+            block_size
+        ));
+    }
+
+    // the last block index and bytes that all need escaping make the longest frame
+    gateway
+        .check_fits(&HostPacket::OtaData(OtaData {
+            index: index_count.saturating_sub(1) as u16,
+            data: std::iter::repeat_n(0xff, block_size).collect(),
+        }))
+        .with_context(|| format!("Blocks of size {} do not fit the gateway", block_size))?;
+
+    let retry = &options.retry;
+    observer.on_phase(Phase::Init);
+    match request(
+        gateway,
+        || HostPacket::OtaGetStatus,
+        options.response_timeout,
+        retry,
+    )? {
+        GatewayPacket::OtaStatus(s) => {
+            if s.in_progress {
+                warn!("Aborting previously started update");
+                match request(
+                    gateway,
+                    || HostPacket::OtaAbortRequest,
+                    options.init_timeout,
+                    retry,
+                )? {
+                    GatewayPacket::OtaAbortAck => {}
+                    p => {
+                        return Err(GatewayError::unexpected("OtaAbortAck", &p))
+                            .context("failed to abort the OTA update left in progress");
+                    }
+                }
+            }
+        }
+        p => {
+            return Err(GatewayError::unexpected("OtaStatus", &p)).context(
+                "failed to initialize the OTA update, the gateway firmware may not support OTA",
+            );
+        }
+    }
+
+    info!(
+        "Initializing the peer update with {} blocks of size {}, {}B total",
+        index_count,
+        block_size,
+        binary.len()
+    );
+    let init = || {
+        HostPacket::OtaInit(OtaInitRequest {
+            destination_address,
+            binary_size: binary.len() as u32,
+            binary_sha256: binary_checksum,
+            block_size: block_size as u16,
+            block_count: index_count as u16,
+        })
+    };
+    match request(gateway, init, options.init_timeout, retry) {
+        Ok(GatewayPacket::OtaInitAck) => observer.on_phase(Phase::Transfer),
+        Err(e) if GatewayError::is_timeout(&e) => {
+            return Err(e.context(InitTimeout(destination_address)))
+        }
+        Err(e) => return Err(e),
+        Ok(p) => {
+            return Err(GatewayError::unexpected("OtaInitAck", &p)).with_context(|| {
+                format!(
+                    "failed to initialize the OTA update, check that node {} is reachable",
+                    destination_address
+                )
+            });
+        }
+    }
+
+    let mut indexes_to_transmit: Vec<u16> = Vec::with_capacity(index_count);
+    let mut highest_index: u16 = 0;
+    let mut last_acked_index: u16 = 0;
+    let mut transmitted_count = 0;
+    let mut retransmitted_count = 0;
+    let mut stalls = 0;
+    let mut was_paused = false;
+    let mut verifying = false;
+    let update_start_time = Instant::now();
+
+    loop {
+        let paused = options.paused.load(Ordering::Relaxed);
+        if paused != was_paused {
+            match paused {
+                true => info!("Paused at block {} of {}", last_acked_index, index_count),
+                false => info!("Resuming"),
+            }
+            was_paused = paused;
+        }
+
+        // only new blocks the node should soon acknowledge count towards a stall
+        let mut may_stall = false;
+        if paused {
+            sleep(PAUSED_POLL);
+            gateway.write(HostPacket::OtaGetStatus)?;
+        } else if indexes_to_transmit.is_empty() && highest_index == index_count as u16 {
+            if !verifying {
+                verifying = true;
+                observer.on_phase(Phase::Verify);
+            }
+            debug!("Requesting ota done status");
+            gateway.write(HostPacket::OtaDoneRequest)?;
+        } else {
+            let retransmission = !indexes_to_transmit.is_empty();
+            may_stall = !retransmission;
+            let i = match indexes_to_transmit.pop() {
+                Some(i) => {
+                    retransmitted_count += 1;
+                    i as usize
+                }
+                None => {
+                    let tmp = highest_index;
+                    if last_acked_index.saturating_add(options.window) >= highest_index {
+                        highest_index += 1;
+                    } else {
+                        debug!(
+                            "not advancing further, last acked {}, highest {}",
+                            last_acked_index, highest_index
+                        );
+                    }
+                    tmp as usize
+                }
+            };
+            let begin = i * block_size;
+            let end = binary.len().min((i + 1) * block_size);
+            debug!("Transmitting block {}", i);
+            transmitted_count += 1;
+            metrics::increment("ota.blocks_sent");
+            gateway.write(HostPacket::OtaData(OtaData {
+                index: i as u16,
+                data: binary[begin..end].iter().cloned().collect(),
+            }))?;
+            observer.on_block_sent(i as u16, retransmission);
+        }
+
+        if may_stall {
+            stalls += 1;
+        }
+        match gateway.read_with_timeout(options.response_timeout) {
+            Ok(packet) => match packet {
+                GatewayPacket::OtaStatus(status) => {
+                    for na in status.not_acked {
+                        if !indexes_to_transmit.contains(&na) {
+                            debug!(
+                                "Scheduling {} to retransmit along with {:?}",
+                                na, indexes_to_transmit
+                            );
+                            indexes_to_transmit.push(na);
+                            metrics::increment("ota.retransmits_scheduled");
+                            observer.on_retransmit(na);
+                        }
+                    }
+                    if status.last_acked != last_acked_index {
+                        stalls = 0;
+                    }
+                    last_acked_index = status.last_acked;
+                    observer.on_ack(&Progress {
+                        block_count: index_count,
+                        acked: last_acked_index as usize,
+                        sent: transmitted_count,
+                        retransmitted: retransmitted_count,
+                        elapsed: update_start_time.elapsed(),
+                        done: false,
+                    });
+                    sleep(options.status_pause);
+                }
+                GatewayPacket::OtaDoneAck => {
+                    let elapsed = update_start_time.elapsed().as_secs_f64();
+                    metrics::gauge("ota.duration_s", elapsed);
+                    metrics::gauge("ota.throughput_bps", binary.len() as f64 / elapsed);
+                    observer.on_complete(&Progress {
+                        block_count: index_count,
+                        acked: index_count,
+                        sent: transmitted_count,
+                        retransmitted: retransmitted_count,
+                        elapsed: update_start_time.elapsed(),
+                        done: true,
+                    });
+                    info!(
+                        "Node {} accepted the image, {} blocks in {:.1} s",
+                        destination_address, index_count, elapsed
+                    );
+                    break;
+                }
+                resp => {
+                    warn!("Unexpected response from gateway during OTA: {:?}", resp);
+                }
+            },
+            Err(e) => {
+                warn!("Error during read: {}", e);
+            }
+        }
+
+        if options.max_stalls != 0 && stalls > options.max_stalls {
+            metrics::increment("ota.stalled");
+            abort(gateway, options);
+            return Err(anyhow!(
+                "the update stalled at block {} of {}, no progress in {} responses",
+                last_acked_index,
+                index_count,
+                options.max_stalls
+            ));
+        }
+        if let Some(max_duration) = options.max_duration.filter(|&m| start.elapsed() > m) {
+            metrics::increment("ota.out_of_time");
+            abort(gateway, options);
+            return Err(anyhow!(
+                "the update ran out of its {} s, aborted at block {} of {} after sending {} \
+                 blocks, {} of them retransmissions",
+                max_duration.as_secs(),
+                last_acked_index,
+                index_count,
+                transmitted_count,
+                retransmitted_count
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Best effort, the node would otherwise keep waiting for the remaining blocks
+fn abort(gateway: &mut GatewayDriver, options: &Options) {
+    if gateway.write(HostPacket::OtaAbortRequest).is_ok() {
+        let _ = gateway.read_with_timeout(options.init_timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(acked: usize, elapsed: u64) -> Progress {
+        Progress {
+            block_count: 6,
+            acked,
+            sent: 4,
+            retransmitted: 0,
+            elapsed: Duration::from_secs(elapsed),
+            done: false,
+        }
+    }
+
+    fn snapshots(path: &Path) -> Vec<String> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .filter_map(|l| {
+                serde_json::from_str::<serde_json::Value>(l).unwrap()["blocks"]
+                    .as_str()
+                    .map(str::to_owned)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn progress_counts_blocks_up_to_the_acknowledged_one() {
+        let halfway = progress(2, 30);
+        assert_eq!(halfway.acked_blocks(), 3);
+        assert_eq!(halfway.percent(), 50.0);
+        assert_eq!(halfway.eta(), Some(Duration::from_secs(30)));
+
+        let last = progress(5, 60);
+        assert_eq!(last.percent(), 100.0);
+        assert_eq!(last.eta(), Some(Duration::ZERO));
+
+        let past_the_end = progress(40, 60);
+        assert_eq!(past_the_end.acked_blocks(), 6);
+        assert_eq!(past_the_end.eta(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn json_log_snapshots_a_running_transfer() {
+        let path = std::env::temp_dir().join(format!("ota-snapshot-{}.jsonl", std::process::id()));
+        let mut log = JsonLog::create(&path).unwrap();
+        for index in 0..4 {
+            log.on_block_sent(index, false);
+        }
+        log.on_retransmit(2);
+        log.on_ack(&progress(1, 0));
+        // past the image, as a confused node could report it
+        log.on_ack(&progress(40, SNAPSHOT_INTERVAL.as_secs()));
+        drop(log);
+        let snapshots = snapshots(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(snapshots, ["aarp..", "aaaaaa"]);
+    }
+
+    /// Stack pointer and reset vector of a Cortex-M image
+    fn vector_table(stack_pointer: u32, reset: u32) -> Vec<u8> {
+        [stack_pointer.to_le_bytes(), reset.to_le_bytes()].concat()
+    }
+
+    #[test]
+    fn vector_table_points_into_ram_and_flash() {
+        let (ram, flash) = (0x2000_0000..0x2001_0000, 0x0800_0000..0x0804_0000);
+        let check = |binary: &[u8]| check_vector_table(binary, &ram, &flash);
+        check(&vector_table(0x2001_0000, 0x0800_0101)).unwrap();
+        for binary in [
+            vector_table(0x2000_0000, 0x0800_0101),
+            vector_table(0x2001_0004, 0x0800_0101),
+            vector_table(0x2000_8002, 0x0800_0101),
+            // an ARM rather than a Thumb address
+            vector_table(0x2001_0000, 0x0800_0100),
+            vector_table(0x2001_0000, 0x0804_0001),
+            b"\x7fELF\x01\x01\x01\x00".to_vec(),
+            vec![0; 7],
+        ] {
+            assert!(check(&binary).is_err(), "{:02x?}", binary);
+        }
+    }
+
+    #[test]
+    fn pad_fills_with_erased_flash() {
+        let binary = [1, 2, 3];
+        assert!(matches!(
+            pad(&binary, None, None).unwrap(),
+            Cow::Borrowed(_)
+        ));
+        assert!(matches!(
+            pad(&binary, None, Some(3)).unwrap(),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(
+            *pad(&binary, Some(5), None).unwrap(),
+            [1, 2, 3, ERASED, ERASED]
+        );
+        assert_eq!(
+            *pad(&binary, Some(5), Some(4)).unwrap(),
+            [1, 2, 3, ERASED, ERASED, ERASED, ERASED, ERASED]
+        );
+        assert_eq!(*pad(&binary, None, Some(2)).unwrap(), [1, 2, 3, ERASED]);
+        assert!(pad(&binary, Some(2), None).is_err());
+        assert!(pad(&binary, None, Some(0)).is_err());
+    }
+}
@@ -0,0 +1,45 @@
+use anyhow::Result;
+use std::{thread::sleep, time::Duration};
+
+/// How often and how patiently to repeat a failing request
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Attempts made after the first one failed
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every further one
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before the given retry (1 based) with +-50% jitter, so that retries of nodes
+    /// sharing the channel do not line up
+    pub fn delay(&self, retry: u32) -> Duration {
+        let delay = self.backoff * 2u32.saturating_pow(retry.saturating_sub(1));
+        delay / 2 + delay.mul_f64(fastrand::f64())
+    }
+
+    /// Calls `f` with the attempt number (1 based) until it succeeds or the retries run out.
+    /// Returns the last result together with the number of attempts made.
+    pub fn run<T>(&self, f: impl FnMut(u32) -> Result<T>) -> (Result<T>, u32) {
+        self.run_if(|_| true, f)
+    }
+
+    /// Like [`Self::run`] but gives up right away on errors `retryable` rejects, e.g. to only
+    /// retry timeouts
+    pub fn run_if<T>(
+        &self,
+        retryable: impl Fn(&anyhow::Error) -> bool,
+        mut f: impl FnMut(u32) -> Result<T>,
+    ) -> (Result<T>, u32) {
+        let mut attempt = 1;
+        loop {
+            let result = f(attempt);
+            match &result {
+                Err(e) if attempt <= self.retries && retryable(e) => {}
+                _ => return (result, attempt),
+            }
+            sleep(self.delay(attempt));
+            attempt += 1;
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use anyhow::{Context, Result};
+use gateway_host_schema::*;
+
+/// Largest node address the gateway can hold, its `usize` is 32 bits wide
+const MAX_ADDRESS: usize = u32::MAX as usize;
+
+struct Variant {
+    name: &'static str,
+    layout: &'static str,
+    smallest: Vec<u8>,
+    /// None when the packet has a list field this binary does not bound
+    largest: Option<Vec<u8>>,
+}
+
+// the matches are exhaustive on purpose, a schema gaining variants fails to build until they
+// are described here
+fn host_name(packet: &HostPacket) -> &'static str {
+    match packet {
+        HostPacket::PingRequest => "PingRequest",
+        HostPacket::OtaGetStatus => "OtaGetStatus",
+        HostPacket::OtaInit(_) => "OtaInit",
+        HostPacket::OtaData(_) => "OtaData",
+        HostPacket::OtaDoneRequest => "OtaDoneRequest",
+        HostPacket::OtaAbortRequest => "OtaAbortRequest",
+        HostPacket::SoilSensor(_) => "SoilSensor",
+    }
+}
+
+fn gateway_name(packet: &GatewayPacket) -> &'static str {
+    match packet {
+        GatewayPacket::PingResponse => "PingResponse",
+        GatewayPacket::OtaStatus(_) => "OtaStatus",
+        GatewayPacket::OtaInitAck => "OtaInitAck",
+        GatewayPacket::OtaDoneAck => "OtaDoneAck",
+        GatewayPacket::OtaAbortAck => "OtaAbortAck",
+        GatewayPacket::SoilSensorMoisture(_) => "SoilSensorMoisture",
+    }
+}
+
+fn host(layout: &'static str, smallest: HostPacket, largest: HostPacket) -> Result<Variant> {
+    let encode = |p: &HostPacket| -> Result<Vec<u8>> {
+        let mut buffer = [0u8; 256];
+        Ok(postcard::to_slice(p, &mut buffer)
+            .with_context(|| format!("Failed to encode {:?}", p))?
+            .to_vec())
+    };
+    Ok(Variant {
+        name: host_name(&smallest),
+        layout,
+        smallest: encode(&smallest)?,
+        largest: Some(encode(&largest)?),
+    })
+}
+
+fn gateway(
+    layout: &'static str,
+    smallest: GatewayPacket,
+    largest: Option<GatewayPacket>,
+) -> Result<Variant> {
+    let encode = |p: &GatewayPacket| -> Result<Vec<u8>> {
+        let mut buffer = [0u8; 256];
+        Ok(postcard::to_slice(p, &mut buffer)
+            .with_context(|| format!("Failed to encode {:?}", p))?
+            .to_vec())
+    };
+    Ok(Variant {
+        name: gateway_name(&smallest),
+        layout,
+        smallest: encode(&smallest)?,
+        largest: largest.as_ref().map(encode).transpose()?,
+    })
+}
+
+fn host_variants(block_size: usize) -> Result<Vec<Variant>> {
+    let init = |address: usize, value: u32, byte: u8, block: u16| {
+        HostPacket::OtaInit(OtaInitRequest {
+            destination_address: address,
+            binary_size: value,
+            binary_sha256: [byte; 32],
+            block_size: block,
+            block_count: block,
+        })
+    };
+    let data = |index: u16, len: usize| {
+        HostPacket::OtaData(OtaData {
+            index,
+            data: std::iter::repeat_n(0xff, len).collect(),
+        })
+    };
+    let sensor = |address: usize| {
+        HostPacket::SoilSensor(SoilSensorRequest {
+            destination_address: address,
+        })
+    };
+    Ok(vec![
+        host("", HostPacket::PingRequest, HostPacket::PingRequest)?,
+        host("", HostPacket::OtaGetStatus, HostPacket::OtaGetStatus)?,
+        host(
+            " { destination_address: usize, binary_size: u32, binary_sha256: [u8; 32], block_size: u16, block_count: u16 }",
+            init(0, 0, 0, 0),
+            init(MAX_ADDRESS, u32::MAX, 0xff, u16::MAX),
+        )?,
+        host(
+            " { index: u16, data: [u8] }",
+            data(0, 0),
+            data(u16::MAX, block_size),
+        )?,
+        host("", HostPacket::OtaDoneRequest, HostPacket::OtaDoneRequest)?,
+        host("", HostPacket::OtaAbortRequest, HostPacket::OtaAbortRequest)?,
+        host(
+            " { destination_address: usize }",
+            sensor(0),
+            sensor(MAX_ADDRESS),
+        )?,
+    ])
+}
+
+fn gateway_variants() -> Result<Vec<Variant>> {
+    Ok(vec![
+        gateway(
+            "",
+            GatewayPacket::PingResponse,
+            Some(GatewayPacket::PingResponse),
+        )?,
+        gateway(
+            " { in_progress: bool, last_acked: u16, not_acked: [u16] }",
+            GatewayPacket::OtaStatus(OtaStatus {
+                in_progress: false,
+                last_acked: 0,
+                not_acked: std::iter::empty().collect(),
+            }),
+            None,
+        )?,
+        gateway(
+            "",
+            GatewayPacket::OtaInitAck,
+            Some(GatewayPacket::OtaInitAck),
+        )?,
+        gateway(
+            "",
+            GatewayPacket::OtaDoneAck,
+            Some(GatewayPacket::OtaDoneAck),
+        )?,
+        gateway(
+            "",
+            GatewayPacket::OtaAbortAck,
+            Some(GatewayPacket::OtaAbortAck),
+        )?,
+        gateway(
+            "([u16; 4])",
+            GatewayPacket::SoilSensorMoisture([0; 4]),
+            Some(GatewayPacket::SoilSensorMoisture([u16::MAX; 4])),
+        )?,
+    ])
+}
+
+fn print_variants(title: &str, variants: &[Variant]) {
+    println!("{}", title);
+    for v in variants {
+        // the postcard varint discriminant fits the first byte for the first 128 variants
+        let size = match &v.largest {
+            Some(largest) if largest.len() == v.smallest.len() => {
+                format!(
+                    "{} B, framed up to {} B",
+                    largest.len(),
+                    largest.len() * 2 + 1
+                )
+            }
+            Some(largest) => format!(
+                "{}..{} B, framed up to {} B",
+                v.smallest.len(),
+                largest.len(),
+                largest.len() * 2 + 1
+            ),
+            None => format!("{} B + 1..3 B per list entry", v.smallest.len()),
+        };
+        println!("  [{}] {}{}: {}", v.smallest[0], v.name, v.layout, size);
+    }
+}
+
+/// Prints every packet variant of the gateway-host-schema this binary was built with, along
+/// with its postcard wire size. Framed sizes account for the 0xFE escaping and the terminator,
+/// OTA data is bounded by the caller's `block_size`.
+pub fn print(block_size: usize) -> Result<()> {
+    print_variants("HostPacket (host -> gateway)", &host_variants(block_size)?);
+    print_variants("GatewayPacket (gateway -> host)", &gateway_variants()?);
+    Ok(())
+}
@@ -0,0 +1,96 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize, Serializer};
+use std::{fmt, path::Path, str::FromStr};
+use tracing::warn;
+
+/// A credential given as is, as `env:<NAME>` to take it from an environment variable or as
+/// `file:<path>` to read it from a file, so it stays out of shell history, process listings
+/// and config files that get shared. Serializes back to how it was given.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Secret {
+    given: String,
+    value: String,
+}
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.value
+    }
+
+    /// The first of `given` and the `var` environment variable that is set
+    pub fn or_env(given: Option<Secret>, var: &str) -> Result<Option<Secret>> {
+        match (given, std::env::var_os(var)) {
+            (Some(secret), _) => Ok(Some(secret)),
+            (None, Some(_)) => format!("env:{}", var).parse().map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+impl FromStr for Secret {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let value = if let Some(var) = s.strip_prefix("env:") {
+            std::env::var(var).with_context(|| format!("Failed to read ${}", var))?
+        } else if let Some(path) = s.strip_prefix("file:") {
+            read_file(Path::new(path))?
+        } else {
+            s.to_owned()
+        };
+        if value.is_empty() {
+            return Err(anyhow!("The credential given as {:?} is empty", s));
+        }
+        Ok(Secret {
+            given: s.to_owned(),
+            value,
+        })
+    }
+}
+
+impl TryFrom<String> for Secret {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.given)
+    }
+}
+
+/// Never prints the value, only how it was given when that does not hold it
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.given == self.value {
+            true => f.write_str("Secret(***)"),
+            false => write!(f, "Secret({})", self.given),
+        }
+    }
+}
+
+/// The file's contents without the trailing newline editors add
+fn read_file(path: &Path) -> Result<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path)
+            .with_context(|| format!("Failed to read the credential file {}", path.display()))?
+            .permissions()
+            .mode();
+        if mode & 0o077 != 0 {
+            warn!(
+                "The credential file {} can be read by other users, chmod 600 it",
+                path.display()
+            );
+        }
+    }
+    Ok(std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the credential file {}", path.display()))?
+        .trim_end_matches(['\r', '\n'])
+        .to_owned())
+}
@@ -0,0 +1,199 @@
+//! Checks of the host↔gateway link, for telling codec bugs apart from radio trouble
+
+use crate::{
+    codec::{self, Decoder, ESCAPE, MAX_FRAME, TERMINATOR},
+    gateway::{GatewayDriver, GatewayError},
+    ota::BLOCK_SIZE,
+};
+use anyhow::Result;
+use gateway_host_schema::*;
+use std::time::{Duration, Instant};
+
+/// Outcome of one check, `failures` is empty when it passed
+pub struct Check {
+    pub name: &'static str,
+    pub summary: String,
+    pub failures: Vec<String>,
+}
+
+impl Check {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Names a payload and gives its byte at each index
+type Pattern = (&'static str, fn(usize) -> u8);
+
+/// Payloads of every length up to past [`MAX_FRAME`]: plain bytes, only bytes that need
+/// escaping, and a mix. Each must survive encoding and decoding, or be refused only when
+/// its escaped form really does not fit.
+pub fn codec() -> Check {
+    let patterns: [Pattern; 3] = [
+        ("plain", |i| (i % ESCAPE as usize) as u8),
+        ("escaped", |i| [ESCAPE, TERMINATOR][i % 2]),
+        ("mixed", |i| [0x00, ESCAPE, 0x7f, TERMINATOR, 0xfd][i % 5]),
+    ];
+    let mut failures = Vec::new();
+    let mut payloads = 0;
+    for (name, byte) in patterns {
+        for len in 0..=MAX_FRAME {
+            payloads += 1;
+            let payload = (0..len).map(byte).collect::<Vec<u8>>();
+            if let Err(e) = round_trip(&payload) {
+                failures.push(format!("{} payload of {}B: {}", name, len, e));
+            }
+        }
+    }
+
+    // the largest packet the host sends must fit a frame however it escapes
+    let block = HostPacket::OtaData(OtaData {
+        index: u16::MAX,
+        data: [TERMINATOR; BLOCK_SIZE].into_iter().collect(),
+    });
+    let mut buffer = [0u8; MAX_FRAME];
+    match postcard::to_slice(&block, &mut buffer) {
+        Ok(serialized) => {
+            payloads += 1;
+            if let Err(e) = round_trip(serialized) {
+                failures.push(format!("full OtaData block: {}", e));
+            }
+        }
+        Err(e) => failures.push(format!("full OtaData block does not serialize: {}", e)),
+    }
+
+    Check {
+        name: "codec",
+        summary: format!("{} payloads", payloads),
+        failures,
+    }
+}
+
+fn round_trip(payload: &[u8]) -> Result<(), String> {
+    let escaped = payload.iter().filter(|&&b| b >= ESCAPE).count();
+    // one more byte for the terminator
+    let fits = payload.len() + escaped < MAX_FRAME;
+    let mut frame = [0u8; MAX_FRAME];
+    let len = match codec::encode(payload, &mut frame) {
+        Ok(len) => len,
+        Err(GatewayError::Overflow) if !fits => return Ok(()),
+        Err(e) => return Err(format!("refused although it fits: {}", e)),
+    };
+    if !fits {
+        return Err(format!("encoded into {}B, more than a frame", len));
+    }
+    if frame[..len - 1].contains(&TERMINATOR) || frame[len - 1] != TERMINATOR {
+        return Err(format!("misplaced terminator in {:02X?}", &frame[..len]));
+    }
+
+    let mut decoder = Decoder::new();
+    for (i, &byte) in frame[..len].iter().enumerate() {
+        match decoder.push(byte) {
+            Ok(done) if done != (i == len - 1) => {
+                return Err(format!("frame ended at byte {} of {}", i + 1, len));
+            }
+            Ok(_) => {}
+            Err(e) => return Err(format!("decoding failed at byte {}: {}", i, e)),
+        }
+    }
+    match decoder.frame() == payload {
+        true => Ok(()),
+        false => Err(format!("decoded into {:02X?}", decoder.frame())),
+    }
+}
+
+/// Pings the gateway `count` times, one at a time
+pub fn ping(gateway: &mut GatewayDriver, count: usize) -> Check {
+    let mut failures = Vec::new();
+    let mut rtts = Vec::new();
+    for n in 0..count {
+        match gateway.ping() {
+            Ok(rtt) => rtts.push(rtt),
+            Err(e) => failures.push(format!("ping {}: {:#}", n + 1, e)),
+        }
+    }
+    let summary = match (rtts.iter().min(), rtts.iter().max()) {
+        (Some(min), Some(max)) => format!(
+            "{} of {} answered, {}..{} ms including the pause after writing",
+            rtts.len(),
+            count,
+            min.as_millis(),
+            max.as_millis()
+        ),
+        _ => format!("none of {} answered", count),
+    };
+    Check {
+        name: "ping",
+        summary,
+        failures,
+    }
+}
+
+/// Sends `count` pings back to back in one write, the gateway has to split and answer
+/// every one of them
+pub fn burst(gateway: &mut GatewayDriver, count: usize) -> Check {
+    let mut failures = Vec::new();
+    let start = Instant::now();
+    let mut answered = 0;
+    match gateway.write_burst(
+        &(0..count)
+            .map(|_| HostPacket::PingRequest)
+            .collect::<Vec<_>>(),
+    ) {
+        Err(e) => failures.push(format!("{:#}", e)),
+        Ok(()) => {
+            for n in 0..count {
+                match gateway.read_with_timeout(Duration::from_secs(1)) {
+                    Ok(GatewayPacket::PingResponse) => answered += 1,
+                    Ok(p) => failures.push(format!(
+                        "response {}: {}",
+                        n + 1,
+                        GatewayError::unexpected("PingResponse", &p)
+                    )),
+                    Err(e) => {
+                        failures.push(format!("response {}: {:#}", n + 1, e));
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    Check {
+        name: "burst",
+        summary: format!(
+            "{} of {} answered in {} ms",
+            answered,
+            count,
+            start.elapsed().as_millis()
+        ),
+        failures,
+    }
+}
+
+/// Asks for the OTA status, which only reads the gateway's state, to check that the
+/// larger and variable sized status frame decodes
+pub fn status(gateway: &mut GatewayDriver) -> Check {
+    let (summary, failures) = match gateway
+        .write(HostPacket::OtaGetStatus)
+        .and_then(|()| gateway.read_with_timeout(Duration::from_secs(3)))
+    {
+        Ok(GatewayPacket::OtaStatus(status)) => (
+            format!(
+                "in progress: {}, {} blocks not acked",
+                status.in_progress,
+                status.not_acked.len()
+            ),
+            Vec::new(),
+        ),
+        Ok(p) => (
+            String::new(),
+            vec![GatewayError::unexpected("OtaStatus", &p).to_string()],
+        ),
+        Err(e) => (String::new(), vec![format!("{:#}", e)]),
+    };
+    Check {
+        name: "status",
+        summary,
+        failures,
+    }
+}
@@ -0,0 +1,312 @@
+//! Status lines and summaries for the terminal, colored when stdout is one
+
+use crate::{
+    logging::LogFormat,
+    ota::{Observer, Phase, Progress},
+};
+use anyhow::Result;
+use clap::ValueEnum;
+use std::{
+    fmt,
+    io::{self, IsTerminal, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ColorChoice {
+    /// Color when writing to a terminal and `NO_COLOR` is not set
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether to color a stream that is or is not a terminal
+    pub fn enabled(self, terminal: bool) -> bool {
+        match self {
+            ColorChoice::Auto => terminal && std::env::var_os("NO_COLOR").is_none(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+/// How summaries are written to stdout
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    Color,
+    Plain,
+    /// One JSON object per summary, for scripts
+    Json,
+}
+
+static MODE: OnceLock<Mode> = OnceLock::new();
+
+/// Set up by [`crate::logging::init`] from the same flags as the diagnostic log
+pub(crate) fn init(format: LogFormat, color: ColorChoice) {
+    let mode = match format {
+        LogFormat::Json => Mode::Json,
+        LogFormat::Text if color.enabled(std::io::stdout().is_terminal()) => Mode::Color,
+        LogFormat::Text => Mode::Plain,
+    };
+    let _ = MODE.set(mode);
+}
+
+/// Plain until [`crate::logging::init`] ran
+pub fn mode() -> Mode {
+    MODE.get().copied().unwrap_or(Mode::Plain)
+}
+
+#[derive(Clone, Copy)]
+pub enum Color {
+    Green,
+    Yellow,
+    Red,
+    Dim,
+}
+
+/// `text` in `color` when coloring, pad it before rather than after as the escape codes
+/// count towards the width
+pub fn paint(text: impl fmt::Display, color: Color) -> String {
+    if mode() != Mode::Color {
+        return text.to_string();
+    }
+    let code = match color {
+        Color::Green => "32",
+        Color::Yellow => "33",
+        Color::Red => "31",
+        Color::Dim => "2",
+    };
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
+
+/// Seconds below a minute, minutes and seconds above
+fn human(duration: Duration) -> String {
+    match duration.as_secs() {
+        0..60 => format!("{:.1} s", duration.as_secs_f64()),
+        secs => format!("{}m {:02}s", secs / 60, secs % 60),
+    }
+}
+
+/// Times the phases of an update as an [`Observer`] and prints them as a table
+#[derive(Default)]
+pub struct Phases {
+    current: Option<(Phase, Instant)>,
+    done: Vec<(Phase, Duration)>,
+}
+
+impl Phases {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn enter(&mut self, phase: Phase) {
+        self.end();
+        self.current = Some((phase, Instant::now()));
+    }
+
+    /// Stops timing the current phase, e.g. while waiting for a scheduled start
+    pub fn end(&mut self) {
+        if let Some((phase, start)) = self.current.take() {
+            self.done.push((phase, start.elapsed()));
+        }
+    }
+
+    /// Adds a phase timed elsewhere
+    pub fn record(&mut self, phase: Phase, took: Duration) {
+        self.done.push((phase, took));
+    }
+
+    /// Prints how long each phase took, the last one is marked failed when `result` is an
+    /// error since the update stopped in it
+    pub fn print(mut self, title: &str, result: &Result<()>) {
+        self.end();
+        end_bar();
+        let failed = |i: usize| result.is_err() && i + 1 == self.done.len();
+        if mode() == Mode::Json {
+            let phases = self
+                .done
+                .iter()
+                .enumerate()
+                .map(|(i, (phase, took))| {
+                    serde_json::json!({
+                        "phase": phase.to_string(),
+                        "seconds": took.as_secs_f64(),
+                        "ok": !failed(i),
+                    })
+                })
+                .collect::<Vec<_>>();
+            println!(
+                "{}",
+                serde_json::json!({ "summary": title, "ok": result.is_ok(), "phases": phases })
+            );
+            return;
+        }
+
+        match result {
+            Ok(()) => println!("{}", paint(title, Color::Green)),
+            Err(_) => println!("{}", paint(title, Color::Red)),
+        }
+        println!("  {}", paint("PHASE     TIME       RESULT", Color::Dim));
+        for (i, (phase, took)) in self.done.iter().enumerate() {
+            let outcome = match failed(i) {
+                true => paint("failed", Color::Red),
+                false => paint("ok", Color::Green),
+            };
+            println!(
+                "  {:<9} {:<10} {}",
+                phase.to_string(),
+                human(*took),
+                outcome
+            );
+        }
+    }
+}
+
+impl Observer for Phases {
+    fn on_phase(&mut self, phase: Phase) {
+        self.enter(phase);
+    }
+}
+
+/// Width of the progress bar on stderr, 0 while none is drawn
+static BAR_WIDTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Stderr for the log, a progress bar is blanked first so log lines do not run into it, the
+/// next acknowledgement draws it again
+pub(crate) fn stderr() -> io::Stderr {
+    let stderr = io::stderr();
+    let width = BAR_WIDTH.swap(0, Ordering::Relaxed);
+    if width > 0 {
+        let _ = write!(stderr.lock(), "\r{:width$}\r", "");
+    }
+    stderr
+}
+
+/// Leaves a drawn progress bar on its own line
+fn end_bar() {
+    if BAR_WIDTH.swap(0, Ordering::Relaxed) > 0 {
+        eprintln!();
+    }
+}
+
+/// How a transfer is shown while it runs, a progress bar or with `verbose` a log line per
+/// block
+pub fn transfer(block_size: usize, verbose: bool) -> Box<dyn Observer> {
+    match verbose {
+        true => Box::new(BlockLog),
+        false => Box::new(ProgressBar::new(block_size)),
+    }
+}
+
+/// A line on stderr redrawn on every acknowledgement, e.g.
+/// `[#########-----------]  312/710 acked  340 sent  28 resent  1480 B/s  ETA 17.2 s`.
+/// Only drawn on a terminal and not with the JSON log.
+pub struct ProgressBar {
+    block_size: usize,
+    enabled: bool,
+    drawn_at: Option<Instant>,
+}
+
+impl ProgressBar {
+    const WIDTH: usize = 20;
+    /// Acknowledgements come every few hundred ms, more redraws would only flicker
+    const REDRAW: Duration = Duration::from_millis(200);
+
+    pub fn new(block_size: usize) -> Self {
+        ProgressBar {
+            block_size,
+            enabled: io::stderr().is_terminal() && mode() != Mode::Json,
+            drawn_at: None,
+        }
+    }
+
+    fn draw(&mut self, progress: &Progress) {
+        if !self.enabled
+            || self
+                .drawn_at
+                .is_some_and(|at| at.elapsed() < Self::REDRAW && !progress.done)
+        {
+            return;
+        }
+        self.drawn_at = Some(Instant::now());
+        let acked = progress.acked_blocks();
+        let filled = (Self::WIDTH * acked / progress.block_count.max(1)).min(Self::WIDTH);
+        let throughput =
+            (acked * self.block_size) as f64 / progress.elapsed.as_secs_f64().max(0.001);
+        let line = format!(
+            "[{}{}] {:>5}/{} acked  {} sent  {} resent  {:.0} B/s  ETA {}",
+            "#".repeat(filled),
+            "-".repeat(Self::WIDTH - filled),
+            acked,
+            progress.block_count,
+            progress.sent,
+            progress.retransmitted,
+            throughput,
+            progress.eta().map_or("?".to_owned(), human),
+        );
+        let previous = BAR_WIDTH.swap(line.len(), Ordering::Relaxed);
+        let _ = write!(
+            io::stderr().lock(),
+            "\r{:width$}",
+            line,
+            width = previous.max(line.len())
+        );
+    }
+}
+
+impl Observer for ProgressBar {
+    fn on_ack(&mut self, progress: &Progress) {
+        self.draw(progress);
+    }
+    fn on_complete(&mut self, progress: &Progress) {
+        self.draw(progress);
+    }
+}
+
+/// A log line per block and acknowledgement, shown with `--verbose` instead of the bar
+pub struct BlockLog;
+
+impl Observer for BlockLog {
+    fn on_block_sent(&mut self, index: u16, retransmission: bool) {
+        match retransmission {
+            true => info!("Retransmitting block {}", index),
+            false => info!("Transmitting block {}", index),
+        }
+    }
+    fn on_ack(&mut self, progress: &Progress) {
+        info!(
+            "Node acknowledged {} of {} blocks",
+            progress.acked_blocks(),
+            progress.block_count
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_bar_survives_acks_past_the_image() {
+        let mut bar = ProgressBar {
+            block_size: 64,
+            enabled: true,
+            drawn_at: None,
+        };
+        bar.on_ack(&Progress {
+            block_count: 10,
+            acked: 400,
+            sent: 12,
+            retransmitted: 2,
+            elapsed: Duration::from_secs(3),
+            done: false,
+        });
+        end_bar();
+    }
+}
@@ -0,0 +1,438 @@
+//! End-to-end tests of the driver against a mock gateway on the other side of a pty pair
+
+use gateway_host_schema::*;
+use lora_host_common::{
+    codec::{self, Decoder, MAX_FRAME},
+    gateway::{GatewayDriver, GatewayError},
+    lock::NodeLock,
+    ota,
+    retry::RetryPolicy,
+    selftest,
+};
+use ring::digest;
+use serialport::TTYPort;
+use std::{
+    collections::BTreeMap,
+    io::{ErrorKind, Read, Write},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+const MOISTURE: [u16; 4] = [310, 420, 530, 640];
+
+/// Decides whether a request gets lost on its way to the gateway
+type Loss = Box<dyn FnMut(&HostPacket) -> bool + Send>;
+
+/// Gateway stand-in answering like the firmware does, keeps the OTA image it received
+#[derive(Default)]
+struct MockGateway {
+    init: Option<OtaInitRequest>,
+    blocks: BTreeMap<u16, Vec<u8>>,
+    /// Done requests answered with the status while the node is still verifying the image
+    verifying: usize,
+}
+
+impl MockGateway {
+    fn status(&self) -> GatewayPacket {
+        let received = self.blocks.keys().next_back().map_or(0, |last| last + 1);
+        let missing = (0..received).filter(|i| !self.blocks.contains_key(i));
+        GatewayPacket::OtaStatus(OtaStatus {
+            in_progress: self.init.is_some(),
+            last_acked: (0..received)
+                .take_while(|i| self.blocks.contains_key(i))
+                .last()
+                .unwrap_or(0),
+            not_acked: missing.collect(),
+        })
+    }
+
+    fn handle(&mut self, packet: HostPacket) -> GatewayPacket {
+        match packet {
+            HostPacket::PingRequest => GatewayPacket::PingResponse,
+            HostPacket::OtaGetStatus => self.status(),
+            HostPacket::OtaInit(init) => {
+                self.init = Some(init);
+                self.blocks.clear();
+                GatewayPacket::OtaInitAck
+            }
+            HostPacket::OtaData(data) => {
+                self.blocks
+                    .insert(data.index, data.data.iter().copied().collect());
+                self.status()
+            }
+            HostPacket::OtaDoneRequest => match &self.init {
+                Some(_) if self.verifying > 0 => {
+                    self.verifying -= 1;
+                    self.status()
+                }
+                Some(init) if self.blocks.len() == init.block_count as usize => {
+                    GatewayPacket::OtaDoneAck
+                }
+                _ => self.status(),
+            },
+            HostPacket::OtaAbortRequest => {
+                *self = MockGateway::default();
+                GatewayPacket::OtaAbortAck
+            }
+            HostPacket::SoilSensor(_) => GatewayPacket::SoilSensorMoisture(MOISTURE),
+        }
+    }
+
+    fn image(&self) -> Vec<u8> {
+        self.blocks.values().flatten().copied().collect()
+    }
+
+    /// Serves requests until the host side of the pty is closed, answering each after `delay`
+    fn spawn(
+        mut gateway: MockGateway,
+        mut port: TTYPort,
+        mut lose: Loss,
+        delay: Duration,
+    ) -> JoinHandle<MockGateway> {
+        thread::spawn(move || {
+            let mut decoder = Decoder::new();
+            loop {
+                let mut recv = [0u8; 1];
+                match port.read(&mut recv) {
+                    Ok(1) => {}
+                    Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                    _ => return gateway,
+                }
+                if !decoder.push(recv[0]).unwrap() {
+                    continue;
+                }
+                let packet = postcard::from_bytes::<HostPacket>(decoder.frame()).unwrap();
+                decoder = Decoder::new();
+                if lose(&packet) {
+                    continue;
+                }
+
+                let response = gateway.handle(packet);
+                thread::sleep(delay);
+                let mut buffer = [0u8; MAX_FRAME];
+                let encoded = postcard::to_slice(&response, &mut buffer).unwrap();
+                let mut frame = [0u8; MAX_FRAME];
+                let len = codec::encode(encoded, &mut frame).unwrap();
+                port.write_all(&frame[..len]).unwrap();
+            }
+        })
+    }
+}
+
+/// Keeps what the OTA loop reported
+#[derive(Default)]
+struct Recorder {
+    resent: Vec<u16>,
+    completed: Option<ota::Progress>,
+    phases: Vec<ota::Phase>,
+}
+
+impl ota::Observer for Recorder {
+    fn on_block_sent(&mut self, index: u16, retransmission: bool) {
+        if retransmission {
+            self.resent.push(index);
+        }
+    }
+    fn on_complete(&mut self, progress: &ota::Progress) {
+        self.completed = Some(progress.clone());
+    }
+    fn on_phase(&mut self, phase: ota::Phase) {
+        self.phases.push(phase);
+    }
+}
+
+fn connect(lose: Loss) -> (GatewayDriver, JoinHandle<MockGateway>) {
+    connect_slow(lose, Duration::ZERO)
+}
+
+fn connect_slow(lose: Loss, delay: Duration) -> (GatewayDriver, JoinHandle<MockGateway>) {
+    connect_to(MockGateway::default(), lose, delay)
+}
+
+fn connect_to(
+    gateway: MockGateway,
+    lose: Loss,
+    delay: Duration,
+) -> (GatewayDriver, JoinHandle<MockGateway>) {
+    // both ends come with a 100 ms read timeout
+    let (master, slave) = TTYPort::pair().unwrap();
+    (
+        GatewayDriver::from_port(Box::new(slave)),
+        MockGateway::spawn(gateway, master, lose, delay),
+    )
+}
+
+#[test]
+fn ping() {
+    let (mut gateway, mock) = connect(Box::new(|_| false));
+    gateway.ping().unwrap();
+    drop(gateway);
+    mock.join().unwrap();
+}
+
+#[test]
+fn ping_waits_for_a_slow_gateway() {
+    let (mut gateway, mock) = connect_slow(Box::new(|_| false), Duration::from_millis(300));
+    gateway.ping().unwrap();
+    gateway.ping().unwrap();
+    // nothing left over from the pings is taken for the reading
+    assert_eq!(gateway.soil_sensor(3).unwrap(), MOISTURE);
+    drop(gateway);
+    mock.join().unwrap();
+}
+
+/// Stands in for the wrong device on the port, answers whatever it reads with `reply`
+fn connect_device(reply: fn(&[u8]) -> Vec<u8>) -> (GatewayDriver, JoinHandle<()>) {
+    let (mut master, slave) = TTYPort::pair().unwrap();
+    let device = thread::spawn(move || loop {
+        let mut recv = [0u8; MAX_FRAME];
+        match master.read(&mut recv) {
+            Ok(len) if len > 0 => master.write_all(&reply(&recv[..len])).unwrap(),
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            _ => return,
+        }
+    });
+    (GatewayDriver::from_port(Box::new(slave)), device)
+}
+
+#[test]
+fn ping_notices_a_device_echoing_queries() {
+    // answers the ping with a frame no gateway sends and everything else with itself
+    let (mut gateway, device) = connect_device(|received| {
+        match postcard::from_bytes::<HostPacket>(&received[..received.len() - 1]) {
+            Ok(HostPacket::PingRequest) => vec![0xfd, 0xff],
+            _ => received.to_vec(),
+        }
+    });
+    let err = gateway.ping().unwrap_err();
+    assert!(
+        matches!(err.downcast_ref(), Some(GatewayError::NotAGateway(_))),
+        "{:#}",
+        err
+    );
+    drop(gateway);
+    device.join().unwrap();
+}
+
+#[test]
+fn read_keeps_the_frame_after_a_malformed_one() {
+    // an escape of a byte that overflows, then a ping response in the same write
+    let (mut gateway, device) = connect_device(|_| {
+        let mut data = [0u8; MAX_FRAME];
+        let data = postcard::to_slice(&GatewayPacket::PingResponse, &mut data).unwrap();
+        let mut reply = vec![0x01, codec::ESCAPE, 0xfe, codec::TERMINATOR];
+        let mut frame = [0u8; MAX_FRAME];
+        let len = codec::encode(data, &mut frame).unwrap();
+        reply.extend_from_slice(&frame[..len]);
+        reply
+    });
+    gateway.write_burst(&[HostPacket::PingRequest]).unwrap();
+    let err = gateway.read().unwrap_err();
+    assert!(format!("{:#}", err).contains("Malformed"), "{:#}", err);
+    assert!(matches!(
+        gateway.read().unwrap(),
+        GatewayPacket::PingResponse
+    ));
+    drop(gateway);
+    device.join().unwrap();
+}
+
+#[test]
+fn ping_notices_a_modem() {
+    let (mut gateway, device) = connect_device(|_| b"\r\nERROR\r\n".to_vec());
+    let err = gateway.ping().unwrap_err();
+    assert!(format!("{:#}", err).contains("\"ERROR\""), "{:#}", err);
+    drop(gateway);
+    device.join().unwrap();
+}
+
+#[test]
+fn selftest_passes() {
+    let (mut gateway, mock) = connect(Box::new(|_| false));
+    for check in [
+        selftest::codec(),
+        selftest::burst(&mut gateway, 5),
+        selftest::status(&mut gateway),
+    ] {
+        assert!(check.passed(), "{}: {:?}", check.name, check.failures);
+    }
+    drop(gateway);
+    mock.join().unwrap();
+}
+
+#[test]
+fn read_gives_up_at_the_deadline() {
+    let (mut gateway, mock) = connect(Box::new(|_| true));
+    gateway.write_burst(&[HostPacket::PingRequest]).unwrap();
+    let start = Instant::now();
+    let err = gateway
+        .read_with_timeout(Duration::from_millis(250))
+        .unwrap_err();
+    let waited = start.elapsed();
+    assert!(GatewayError::is_timeout(&err), "{:#}", err);
+    assert!(
+        waited >= Duration::from_millis(250) && waited < Duration::from_millis(350),
+        "{:?}",
+        waited
+    );
+    drop(gateway);
+    mock.join().unwrap();
+}
+
+#[test]
+fn sensor_poll_survives_a_lost_request() {
+    let mut lost = false;
+    let (mut gateway, mock) = connect(Box::new(move |p| {
+        let lose = matches!(p, HostPacket::SoilSensor(_)) && !lost;
+        lost |= lose;
+        lose
+    }));
+
+    let retry = RetryPolicy {
+        retries: 2,
+        backoff: Duration::from_millis(10),
+    };
+    let (reading, attempts) = retry.run(|_| gateway.soil_sensor(3));
+    assert_eq!(reading.unwrap(), MOISTURE);
+    assert_eq!(attempts, 2);
+
+    drop(gateway);
+    mock.join().unwrap();
+}
+
+#[test]
+fn ota_transfer_recovers_a_lost_block() {
+    // not a multiple of the block size and full of bytes that need escaping
+    let binary = (0..ota::BLOCK_SIZE * 3 - 20)
+        .map(|i| [0xfe, 0xff, i as u8][i % 3])
+        .collect::<Vec<u8>>();
+    let mut lost = false;
+    let (mut gateway, mock) = connect(Box::new(move |p| {
+        let lose = matches!(p, HostPacket::OtaData(d) if d.index == 1) && !lost;
+        lost |= lose;
+        lose
+    }));
+
+    let mut recorder = Recorder::default();
+    let log_path = std::env::temp_dir().join(format!("ota-debug-{}.jsonl", std::process::id()));
+    let log = ota::JsonLog::create(&log_path).unwrap();
+    ota::update(
+        &mut gateway,
+        7,
+        &binary,
+        &ota::Options::default(),
+        &mut (&mut recorder, log),
+    )
+    .unwrap();
+    drop(gateway);
+    let mock = mock.join().unwrap();
+
+    let init = mock.init.as_ref().unwrap();
+    assert_eq!(init.destination_address, 7);
+    assert_eq!(init.binary_size as usize, binary.len());
+    assert_eq!(
+        init.binary_sha256.as_slice(),
+        digest::digest(&digest::SHA256, &mock.image()).as_ref()
+    );
+    assert_eq!(mock.image(), binary);
+
+    assert_eq!(recorder.resent, [1]);
+    assert_eq!(
+        recorder.phases,
+        [ota::Phase::Init, ota::Phase::Transfer, ota::Phase::Verify]
+    );
+    let completed = recorder.completed.unwrap();
+    assert_eq!(completed.block_count, 3);
+    assert_eq!(completed.percent(), 100.0);
+    assert_eq!(completed.retransmitted, 1);
+
+    let log = std::fs::read_to_string(&log_path).unwrap();
+    std::fs::remove_file(&log_path).unwrap();
+    let snapshots = log
+        .lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .filter_map(|v| v["blocks"].as_str().map(str::to_owned))
+        .collect::<Vec<String>>();
+    assert_eq!(snapshots.first().unwrap().len(), 3);
+    assert_eq!(snapshots.last().unwrap(), "aaa");
+}
+
+#[test]
+fn ota_waits_for_a_node_verifying_slowly() {
+    let mock = MockGateway {
+        verifying: 5,
+        ..Default::default()
+    };
+    let (mut gateway, mock) = connect_to(mock, Box::new(|_| false), Duration::ZERO);
+    let options = ota::Options {
+        max_stalls: 2,
+        ..Default::default()
+    };
+    let binary = [0x5a; 100];
+    ota::update(&mut gateway, 6, &binary, &options, &mut ()).unwrap();
+    drop(gateway);
+    let mock = mock.join().unwrap();
+    assert_eq!(mock.verifying, 0);
+    assert_eq!(mock.image(), binary);
+}
+
+#[test]
+fn ota_reports_a_node_that_does_not_answer() {
+    let (mut gateway, mock) = connect(Box::new(|p| matches!(p, HostPacket::OtaInit(_))));
+    let options = ota::Options {
+        init_timeout: Duration::from_millis(300),
+        ..Default::default()
+    };
+    let err = ota::update(&mut gateway, 5, &[0; 10], &options, &mut ()).unwrap_err();
+    assert!(
+        err.downcast_ref::<ota::InitTimeout>()
+            .is_some_and(|e| e.0 == 5),
+        "{:#}",
+        err
+    );
+    drop(gateway);
+    mock.join().unwrap();
+}
+
+#[test]
+fn ota_refuses_blocks_longer_than_the_gateway_frame() {
+    let (mut gateway, mock) = connect(Box::new(|_| false));
+    gateway.set_max_frame(64).unwrap();
+    let err = ota::update(
+        &mut gateway,
+        4,
+        &[0; 1000],
+        &ota::Options::default(),
+        &mut (),
+    )
+    .unwrap_err();
+    assert!(
+        matches!(
+            err.downcast_ref(),
+            Some(GatewayError::FrameTooLong { max: 64, .. })
+        ),
+        "{:#}",
+        err
+    );
+    drop(gateway);
+    assert!(mock.join().unwrap().init.is_none());
+}
+
+#[test]
+fn ota_refuses_a_node_being_updated() {
+    let (mut gateway, mock) = connect(Box::new(|_| false));
+    let lock = NodeLock::acquire(9).unwrap();
+    let err =
+        ota::update(&mut gateway, 9, &[0; 10], &ota::Options::default(), &mut ()).unwrap_err();
+    assert!(
+        err.to_string().contains("already being updated"),
+        "{:#}",
+        err
+    );
+    drop(lock);
+    NodeLock::acquire(9).unwrap();
+
+    drop(gateway);
+    let mock = mock.join().unwrap();
+    assert!(mock.init.is_none());
+}
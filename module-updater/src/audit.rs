@@ -0,0 +1,119 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use lora_host_common::ota::{Observer, Progress};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    time::Duration,
+};
+
+pub const DEFAULT_PATH: &str = "audit.jsonl";
+
+/// One update attempt, the audit file holds one JSON object per line and is only appended to
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub time: String,
+    pub operator: String,
+    pub node: usize,
+    pub image_sha256: String,
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_s: f64,
+    pub blocks_sent: usize,
+    pub blocks_retransmitted: usize,
+}
+
+impl Entry {
+    pub fn new(
+        operator: &str,
+        node: usize,
+        image_sha256: &str,
+        result: &Result<()>,
+        duration: Duration,
+        stats: &Stats,
+    ) -> Entry {
+        Entry {
+            time: Utc::now().to_rfc3339(),
+            operator: operator.to_owned(),
+            node,
+            image_sha256: image_sha256.to_owned(),
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            duration_s: duration.as_secs_f64(),
+            blocks_sent: stats.0.as_ref().map_or(0, |p| p.sent),
+            blocks_retransmitted: stats.0.as_ref().map_or(0, |p| p.retransmitted),
+        }
+    }
+}
+
+/// Keeps the latest progress of a transfer for its audit entry
+#[derive(Default)]
+pub struct Stats(Option<Progress>);
+
+impl Observer for Stats {
+    fn on_ack(&mut self, progress: &Progress) {
+        self.0 = Some(progress.clone());
+    }
+    fn on_complete(&mut self, progress: &Progress) {
+        self.0 = Some(progress.clone());
+    }
+}
+
+/// The local user, which is who ran the update unless told otherwise
+pub fn default_operator() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_owned())
+}
+
+pub fn append(path: &Path, entry: &Entry) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open the audit log {}", path.display()))?;
+    file.write_all(format!("{}\n", serde_json::to_string(entry)?).as_bytes())
+        .with_context(|| format!("Failed to append to the audit log {}", path.display()))
+}
+
+/// Prints the latest `limit` entries, oldest first, optionally only those of one node
+pub fn print_history(path: &Path, node: Option<usize>, limit: usize) -> Result<()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .with_context(|| format!("Failed to open the audit log {}", path.display()))?;
+    let mut entries = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let entry: Entry = serde_json::from_str(&line?)
+            .with_context(|| format!("Malformed entry on line {} of {}", n + 1, path.display()))?;
+        if node.is_none_or(|node| node == entry.node) {
+            entries.push(entry);
+        }
+    }
+
+    println!(
+        "{:<26} {:<12} {:>8} {:<12} {:<7} {:>9} {:>6} {:>8}",
+        "TIME", "OPERATOR", "NODE", "IMAGE", "RESULT", "DURATION", "SENT", "RESENT"
+    );
+    for entry in &entries[entries.len().saturating_sub(limit)..] {
+        println!(
+            "{:<26} {:<12} {:>8} {:<12} {:<7} {:>8}s {:>6} {:>8}{}",
+            entry.time,
+            entry.operator,
+            entry.node,
+            &entry.image_sha256[..entry.image_sha256.len().min(12)],
+            if entry.success { "ok" } else { "failed" },
+            entry.duration_s.round(),
+            entry.blocks_sent,
+            entry.blocks_retransmitted,
+            entry
+                .error
+                .as_ref()
+                .map_or(String::new(), |e| format!("  {}", e))
+        );
+    }
+    Ok(())
+}
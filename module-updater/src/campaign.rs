@@ -0,0 +1,285 @@
+//! Staged rollout of one image across the fleet, driven by a YAML plan:
+//!
+//! ```yaml
+//! binary: firmware.bin        # relative to the plan
+//! canary_percent: 10          # of the first wave, updated before the rest of it
+//! max_failure_rate: 0.2       # stop when more of a wave fails
+//! waves:
+//!   - [3, 4, 5]
+//!   - [6, "tag:greenhouse"]   # tags are looked up in the inventory
+//! ```
+
+use crate::Settings;
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use lora_host_common::{
+    addressbook::AddressBook,
+    gateway::GatewayDriver,
+    inventory::{self, Inventory, Target},
+    ota,
+    term::{self, Color},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Plan {
+    binary: PathBuf,
+    #[serde(default)]
+    canary_percent: f64,
+    #[serde(default)]
+    max_failure_rate: f64,
+    waves: Vec<Vec<Target>>,
+}
+
+/// Persisted between runs so an interrupted or aborted campaign resumes where it stopped
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    image_sha256: String,
+    nodes: BTreeMap<usize, NodeState>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NodeState {
+    updated: bool,
+    at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+struct Campaign<'a> {
+    settings: &'a Settings,
+    gateway: GatewayDriver,
+    inventory: Inventory,
+    binary: &'a [u8],
+    checksum: [u8; 32],
+    state: State,
+    state_path: PathBuf,
+}
+
+impl Campaign<'_> {
+    fn is_updated(&self, node: usize) -> bool {
+        self.state.nodes.get(&node).is_some_and(|n| n.updated)
+    }
+
+    /// Updates the nodes one by one, returns how many failed
+    fn update(&mut self, nodes: &[usize]) -> Result<usize> {
+        let mut failed = 0;
+        for &node in nodes {
+            info!("Updating node {}", node);
+            let result = self.settings.update_node(
+                &mut self.gateway,
+                node,
+                self.inventory.fallback_address(node),
+                self.binary,
+                &self.checksum,
+                &mut (),
+            );
+            if let Err(e) = &result {
+                warn!("Node {}: update failed: {:#}", node, e);
+                failed += 1;
+            }
+            self.state.nodes.insert(
+                node,
+                NodeState {
+                    updated: result.is_ok(),
+                    at: Utc::now().to_rfc3339(),
+                    error: result.err().map(|e| format!("{:#}", e)),
+                },
+            );
+            self.save()?;
+        }
+        Ok(failed)
+    }
+
+    fn save(&self) -> Result<()> {
+        std::fs::write(&self.state_path, serde_json::to_string_pretty(&self.state)?)
+            .with_context(|| format!("Failed to write {}", self.state_path.display()))
+    }
+}
+
+/// The addresses of each wave, a node already in an earlier wave or earlier in the same one
+/// is left out
+fn resolve_waves(
+    waves: &[Vec<Target>],
+    inventory: &Inventory,
+    book: &AddressBook,
+) -> Result<Vec<Vec<usize>>> {
+    let mut resolved: Vec<Vec<usize>> = Vec::new();
+    for wave in waves {
+        let mut nodes = Vec::new();
+        for target in wave {
+            for node in inventory.resolve(target, book)? {
+                if !resolved.iter().flatten().chain(&nodes).any(|&n| n == node) {
+                    nodes.push(node);
+                }
+            }
+        }
+        resolved.push(nodes);
+    }
+    Ok(resolved)
+}
+
+/// Canaries of the first wave, `canary_percent` of the whole wave rounded up but at least one
+/// and at most the nodes still pending
+fn canary_count(canary_percent: f64, wave: usize, pending: usize) -> usize {
+    match canary_percent > 0.0 {
+        true => ((wave as f64 * canary_percent / 100.0).ceil() as usize).clamp(1, pending),
+        false => 0,
+    }
+}
+
+pub fn run(plan_path: &Path, settings: &Settings) -> Result<()> {
+    let plan: Plan = serde_yaml::from_str(
+        &std::fs::read_to_string(plan_path)
+            .with_context(|| format!("Failed to read {}", plan_path.display()))?,
+    )
+    .with_context(|| format!("Failed to parse {}", plan_path.display()))?;
+    if !(0.0..=100.0).contains(&plan.canary_percent) {
+        return Err(anyhow!("canary_percent must be between 0 and 100"));
+    }
+
+    let inventory = Inventory::load(&settings.inventory)?;
+    let book = AddressBook::load(&settings.addressbook)?;
+    let waves = resolve_waves(&plan.waves, &inventory, &book)?;
+
+    let binary_path = plan_path
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join(&plan.binary);
+    let mapped = ota::map_binary(&binary_path)?;
+    let binary = settings.prepare(&mapped)?;
+    let checksum = ota::checksum(&binary);
+    let image_sha256 = inventory::hex(&checksum);
+
+    let state_path = plan_path.with_extension("state.json");
+    let state = match std::fs::read_to_string(&state_path) {
+        Ok(text) => serde_json::from_str::<State>(&text)
+            .with_context(|| format!("Failed to parse {}", state_path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => State::default(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", state_path.display()))
+        }
+    };
+    let state = if state.image_sha256 == image_sha256 {
+        state
+    } else {
+        if !state.nodes.is_empty() {
+            info!("The plan's image changed, starting the campaign over");
+        }
+        State {
+            image_sha256,
+            nodes: BTreeMap::new(),
+        }
+    };
+
+    let mut campaign = Campaign {
+        settings,
+        gateway: settings.connect()?,
+        inventory,
+        binary: &binary,
+        checksum,
+        state,
+        state_path,
+    };
+
+    settings.wait_for_start(&mut campaign.gateway)?;
+    for (n, wave) in waves.iter().enumerate() {
+        let pending = wave
+            .iter()
+            .copied()
+            .filter(|&node| !campaign.is_updated(node))
+            .collect::<Vec<usize>>();
+        if pending.is_empty() {
+            info!("Wave {} is already updated", n + 1);
+            continue;
+        }
+
+        let canaries = match n {
+            0 => canary_count(plan.canary_percent, wave.len(), pending.len()),
+            _ => 0,
+        };
+        let (canary, rest) = pending.split_at(canaries);
+        if !canary.is_empty() {
+            info!("Wave {}: updating the canaries {:?}", n + 1, canary);
+            if campaign.update(canary)? > 0 {
+                return Err(anyhow!("A canary failed, stopping the campaign"));
+            }
+        }
+
+        info!("Wave {}: updating {:?}", n + 1, rest);
+        let failed = campaign.update(rest)?;
+        let failure_rate = failed as f64 / pending.len() as f64;
+        if failure_rate > plan.max_failure_rate {
+            return Err(anyhow!(
+                "{} of {} nodes in wave {} failed, over the allowed failure rate of {}, \
+                 stopping the campaign",
+                failed,
+                pending.len(),
+                n + 1,
+                plan.max_failure_rate
+            ));
+        }
+    }
+
+    let failed = waves
+        .iter()
+        .flatten()
+        .filter(|&&node| !campaign.is_updated(node))
+        .collect::<Vec<&usize>>();
+    match failed.is_empty() {
+        true => {
+            println!("{}", term::paint("Campaign complete", Color::Green));
+            Ok(())
+        }
+        false => Err(anyhow!(
+            "Campaign finished with failed nodes {:?}, rerun to retry them",
+            failed
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inventory() -> Inventory {
+        let mut inventory = Inventory::empty(Path::new("inventory.json"));
+        for (address, tag) in [(6, "greenhouse"), (7, "greenhouse"), (8, "field")] {
+            inventory.node_mut(address).tags.insert(tag.to_owned());
+        }
+        inventory
+    }
+
+    #[test]
+    fn waves_leave_out_nodes_of_earlier_waves() {
+        let plan: Plan = serde_yaml::from_str(
+            "binary: firmware.bin\nwaves:\n  - [3, 6, 3]\n  - [\"tag:greenhouse\", 8]\n",
+        )
+        .unwrap();
+        let book = AddressBook::load(Path::new("no-such-addressbook.json")).unwrap();
+        let waves = resolve_waves(&plan.waves, &inventory(), &book).unwrap();
+        assert_eq!(waves, vec![vec![3, 6], vec![7, 8]]);
+    }
+
+    #[test]
+    fn waves_refuse_an_unknown_tag() {
+        let waves = vec![vec![Target::Tag("orchard".to_owned())]];
+        let book = AddressBook::load(Path::new("no-such-addressbook.json")).unwrap();
+        assert!(resolve_waves(&waves, &inventory(), &book).is_err());
+    }
+
+    #[test]
+    fn canaries_round_up_within_the_pending_nodes() {
+        assert_eq!(canary_count(0.0, 20, 20), 0);
+        assert_eq!(canary_count(10.0, 25, 25), 3);
+        assert_eq!(canary_count(10.0, 3, 3), 1);
+        // a resumed wave has fewer nodes left than canaries
+        assert_eq!(canary_count(50.0, 10, 2), 2);
+    }
+}
@@ -1,38 +1,774 @@
-mod gateway;
+mod audit;
+mod campaign;
+mod progress;
 
 use anyhow::{anyhow, Context, Result};
-use clap::Parser;
-use gateway::GatewayDriver;
-use gateway_host_schema::*;
-use ring::digest;
-use std::{fs::File, io::Write, path::Path, thread::sleep, time::{Duration, Instant}};
+use chrono::{DateTime, FixedOffset, Utc};
+use clap::{Parser, Subcommand};
+use lora_host_common::{
+    addressbook::{self, AddressBook, AddressBookAction, NodeRef},
+    gateway::{Baudrate, GatewayDriver},
+    inventory::{self, Inventory, Target},
+    logging::{self, LogArgs},
+    metrics,
+    ota::{self, Phase, BLOCK_SIZE},
+    retry::RetryPolicy,
+    schema,
+    secret::Secret,
+    term::{self, Color, Phases},
+};
+use progress::Publisher;
+use serde::Deserialize;
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    fs::OpenOptions,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    thread::sleep,
+    time::{Duration, Instant},
+};
+use tracing::{error, info, warn};
+
+const DEFAULT_CONFIG: &str = "module-updater.toml";
+/// STM32WL55 SRAM and flash, the modules' MCU
+const DEFAULT_RAM: Range<u32> = 0x2000_0000..0x2001_0000;
+const DEFAULT_FLASH: Range<u32> = 0x0800_0000..0x0804_0000;
+/// Pings repeated while waiting for a scheduled start before the gateway counts as gone,
+/// about half a minute of silence
+const WAIT_PING_RETRY: RetryPolicy = RetryPolicy {
+    retries: 4,
+    backoff: Duration::from_secs(2),
+};
 
 /// LoRa module OTA updater
 #[derive(Parser)]
 struct Args {
-    /// The device path to a serialport
-    port: String,
+    #[clap(flatten)]
+    log: LogArgs,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Update the firmware of a node over the air
+    Update(UpdateArgs),
+    /// Roll an image out to the fleet in waves following a YAML plan, rerun to resume
+    Campaign {
+        /// The rollout plan, its progress is kept in <plan>.state.json next to it
+        plan: PathBuf,
+
+        #[clap(flatten)]
+        transfer: TransferArgs,
+    },
+    /// Show the known nodes, or edit what is kept about one by hand
+    Inventory {
+        /// TOML file naming the inventory, module-updater.toml is used if present
+        #[clap(short, long)]
+        config: Option<PathBuf>,
+
+        /// The inventory file [default: inventory.json]
+        #[clap(long)]
+        inventory: Option<PathBuf>,
+
+        #[clap(subcommand)]
+        action: Option<InventoryAction>,
+    },
+    /// Show past update attempts from the audit log
+    History {
+        /// TOML file naming the audit log, module-updater.toml is used if present
+        #[clap(short, long)]
+        config: Option<PathBuf>,
+
+        /// The audit log [default: audit.jsonl]
+        #[clap(long)]
+        audit_log: Option<PathBuf>,
+
+        /// The address book resolving --node names [default: addressbook.json]
+        #[clap(long)]
+        addressbook: Option<PathBuf>,
+
+        /// Only show attempts on this node, an address or a name from the address book
+        #[clap(long)]
+        node: Option<NodeRef>,
+
+        /// Show at most this many of the latest attempts
+        #[clap(long, default_value = "50")]
+        limit: usize,
+    },
+    /// Tell whether nodes run an image, by the hash the inventory recorded when they were
+    /// last updated, exits with an error when any may not
+    Diff {
+        /// Path to the firmware binary
+        binary: PathBuf,
+
+        /// The node address or name, or tag:<name> for every node with that tag in the inventory
+        target: Target,
+
+        /// TOML file naming the inventory and padding, module-updater.toml is used if present
+        #[clap(short, long)]
+        config: Option<PathBuf>,
+
+        /// The inventory file [default: inventory.json]
+        #[clap(long)]
+        inventory: Option<PathBuf>,
+
+        /// Padding the image was updated with, as given to update
+        #[clap(long, value_parser = parse_size)]
+        pad_to: Option<usize>,
+
+        /// Alignment the image was updated with, as given to update
+        #[clap(long, value_parser = parse_size)]
+        align: Option<usize>,
+
+        /// The address book resolving node names [default: addressbook.json]
+        #[clap(long)]
+        addressbook: Option<PathBuf>,
+    },
+    /// Show the node names, or name a node so commands can be given the name instead
+    Addressbook {
+        /// TOML file naming the address book, module-updater.toml is used if present
+        #[clap(short, long)]
+        config: Option<PathBuf>,
+
+        /// The address book file [default: addressbook.json]
+        #[clap(long)]
+        addressbook: Option<PathBuf>,
+
+        #[clap(subcommand)]
+        action: Option<AddressBookAction>,
+    },
+    /// List the packets of the gateway-host-schema this binary was built with
+    Schema,
+}
 
-    /// The node address
-    destination_address: usize,
+#[derive(Subcommand)]
+enum InventoryAction {
+    /// Set the name, hardware revision or tags of a node
+    Set {
+        address: usize,
+
+        #[clap(long)]
+        name: Option<String>,
+
+        #[clap(long)]
+        hardware_rev: Option<String>,
+
+        /// Address to retry the update at when the node does not answer, e.g. its bootloader's
+        #[clap(long)]
+        fallback_address: Option<usize>,
+
+        /// Add a tag, can be repeated
+        #[clap(long)]
+        tag: Vec<String>,
+
+        /// Remove a tag, can be repeated
+        #[clap(long)]
+        untag: Vec<String>,
+    },
+    /// Forget a node
+    Remove { address: usize },
+}
+
+#[derive(clap::Args)]
+struct UpdateArgs {
+    /// The node address or name, or tag:<name> for every node with that tag in the inventory
+    target: Target,
 
     /// Path to the firmware binary
     binary: String,
 
-    /// The baudrate to open the port with
-    #[clap(short, long, default_value = "115200")]
-    baudrate: u32,
+    #[clap(flatten)]
+    transfer: TransferArgs,
+
+    /// Address to retry at when the node does not acknowledge the start of the update, e.g.
+    /// its bootloader's, only for a single node [default: from the inventory]
+    #[clap(long)]
+    fallback_address: Option<usize>,
 
-    /// Diagnostic file output path
+    /// Diagnostic file output path, only for a single node, JSON lines with block
+    /// snapshots when it ends in .jsonl, CSV otherwise
     #[clap(long, default_value=None)]
-    debug_file: Option<String>
+    debug_file: Option<String>,
+}
+
+/// Connection and transfer options, shared by the commands updating nodes
+#[derive(clap::Args)]
+struct TransferArgs {
+    /// TOML file with defaults for the options below, module-updater.toml is used if present
+    #[clap(short, long)]
+    config: Option<PathBuf>,
+
+    /// The serialport of the gateway, e.g. /dev/ttyACM0 or COM3, `auto` picks the only USB one
+    #[clap(short, long)]
+    port: Option<String>,
+
+    /// The baudrate to open the port with, `auto` tries the common ones [default: 115200]
+    #[clap(short, long)]
+    baudrate: Option<Baudrate>,
+
+    /// Payload bytes per OTA block [default: 64]
+    #[clap(long)]
+    block_size: Option<usize>,
+
+    /// Longest frame the gateway buffers, terminator included, longer ones are refused
+    /// before they are sent [default: 256]
+    #[clap(long)]
+    max_frame: Option<usize>,
+
+    /// How long the node may take to start or abort an update [default: 30000]
+    #[clap(long)]
+    init_timeout_ms: Option<u64>,
+
+    /// How long to wait for the gateway to answer during the transfer [default: 3000]
+    #[clap(long)]
+    response_timeout_ms: Option<u64>,
+
+    /// Blocks that may be sent past the last acknowledged one [default: 12]
+    #[clap(long)]
+    window: Option<u16>,
+
+    /// Pause after each status from the gateway [default: 150]
+    #[clap(long)]
+    status_pause_ms: Option<u64>,
+
+    /// Abort after this many new blocks without the acknowledgement advancing, 0 never gives
+    /// up [default: 0]
+    #[clap(long)]
+    max_stalls: Option<u32>,
+
+    /// Abort an update still running after this long, e.g. 45m or 2h, so it ends within
+    /// the maintenance window
+    #[clap(long, value_parser = parse_duration)]
+    max_duration: Option<Duration>,
+
+    /// Refuse an image not starting with a Cortex-M vector table that points into --ram and
+    /// --flash [default: vector_check in the config, then off]
+    #[clap(long)]
+    vector_check: bool,
+
+    /// RAM the initial stack pointer must be in [default: 0x20000000..0x20010000]
+    #[clap(long, value_parser = parse_range)]
+    ram: Option<Range<u32>>,
+
+    /// Flash the reset vector must be in [default: 0x08000000..0x08040000]
+    #[clap(long, value_parser = parse_range)]
+    flash: Option<Range<u32>>,
+
+    /// Pad the image with 0xFF up to this many bytes, decimal or 0x hex
+    #[clap(long, value_parser = parse_size)]
+    pad_to: Option<usize>,
+
+    /// Pad the image with 0xFF to a multiple of this many bytes, decimal or 0x hex
+    #[clap(long, value_parser = parse_size)]
+    align: Option<usize>,
+
+    /// POST JSON progress events (percent, retransmit rate, ETA) to this URL, `env:<NAME>` or
+    /// `file:<path>` when it carries a token
+    #[clap(long)]
+    progress_url: Option<Secret>,
+
+    /// Nodes updated successfully are recorded in this file [default: inventory.json]
+    #[clap(long)]
+    inventory: Option<PathBuf>,
+
+    /// Every update attempt is appended to this file [default: audit.jsonl]
+    #[clap(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Names the nodes can be targeted by [default: addressbook.json]
+    #[clap(long)]
+    addressbook: Option<PathBuf>,
+
+    /// Who is running the update, for the audit log [default: $USER]
+    #[clap(long)]
+    operator: Option<String>,
+
+    /// Connect and check the image right away but start the transfer at this RFC 3339
+    /// time, e.g. 2024-05-01T02:00:00+02:00
+    #[clap(long, value_parser = DateTime::parse_from_rfc3339, conflicts_with = "start_in")]
+    start_at: Option<DateTime<FixedOffset>>,
+
+    /// Like --start-at but after a delay, e.g. 90s, 15m or 1h30m
+    #[clap(long, value_parser = parse_duration)]
+    start_in: Option<Duration>,
+
+    /// Log every block sent and acknowledged instead of drawing a progress bar
+    #[clap(short, long)]
+    verbose: bool,
+}
+
+/// Contents of the config file, every field can be overridden on the command line
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    port: Option<String>,
+    baudrate: Option<Baudrate>,
+    block_size: Option<usize>,
+    max_frame: Option<usize>,
+    init_timeout_ms: Option<u64>,
+    response_timeout_ms: Option<u64>,
+    window: Option<u16>,
+    status_pause_ms: Option<u64>,
+    max_stalls: Option<u32>,
+    max_duration: Option<String>,
+    vector_check: Option<bool>,
+    ram: Option<String>,
+    flash: Option<String>,
+    pad_to: Option<usize>,
+    align: Option<usize>,
+    progress_url: Option<Secret>,
+    inventory: Option<PathBuf>,
+    audit_log: Option<PathBuf>,
+    addressbook: Option<PathBuf>,
+}
+
+impl Config {
+    /// Reads the given file, or the default one when it exists
+    fn load(path: Option<&Path>) -> Result<Config> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG).is_file() => Path::new(DEFAULT_CONFIG),
+            None => return Ok(Config::default()),
+        };
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&text)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+}
+
+/// Options resolved from the command line, the config file and the defaults
+struct Settings {
+    port: String,
+    baudrate: Baudrate,
+    max_frame: Option<usize>,
+    options: ota::Options,
+    /// RAM and flash ranges the vector table is checked against
+    vector_check: Option<(Range<u32>, Range<u32>)>,
+    pad_to: Option<usize>,
+    align: Option<usize>,
+    progress_url: Option<Secret>,
+    inventory: PathBuf,
+    audit_log: PathBuf,
+    addressbook: PathBuf,
+    operator: String,
+    /// When the transfer may start, see [`Settings::wait_for_start`]
+    start: Option<DateTime<Utc>>,
+    /// How long [`Settings::connect`] took, shown in the summary of the first update
+    connected_in: Cell<Option<Duration>>,
+    verbose: bool,
 }
 
-const INIT_TIMEOUT: Duration = Duration::from_secs(30);
-const RESPONSE_TIMEOUT: Duration = Duration::from_secs(3);
+impl TransferArgs {
+    fn resolve(self) -> Result<Settings> {
+        let config = Config::load(self.config.as_deref())?;
+        let port = self.port.or(config.port).ok_or(anyhow!(
+            "No port given, pass --port or set \"port\" in the config file"
+        ))?;
+        let baudrate = self
+            .baudrate
+            .or(config.baudrate)
+            .unwrap_or(Baudrate::Fixed(115200));
+        let defaults = ota::Options::default();
+        let options = ota::Options {
+            block_size: self
+                .block_size
+                .or(config.block_size)
+                .unwrap_or(defaults.block_size),
+            init_timeout: self
+                .init_timeout_ms
+                .or(config.init_timeout_ms)
+                .map_or(defaults.init_timeout, Duration::from_millis),
+            response_timeout: self
+                .response_timeout_ms
+                .or(config.response_timeout_ms)
+                .map_or(defaults.response_timeout, Duration::from_millis),
+            window: self.window.or(config.window).unwrap_or(defaults.window),
+            status_pause: self
+                .status_pause_ms
+                .or(config.status_pause_ms)
+                .map_or(defaults.status_pause, Duration::from_millis),
+            max_stalls: self
+                .max_stalls
+                .or(config.max_stalls)
+                .unwrap_or(defaults.max_stalls),
+            max_duration: match (self.max_duration, config.max_duration) {
+                (Some(max), _) => Some(max),
+                (None, Some(max)) => {
+                    Some(parse_duration(&max).context("Invalid max_duration in the config file")?)
+                }
+                (None, None) => None,
+            },
+            paused: defaults.paused,
+            retry: defaults.retry,
+        };
+        #[cfg(unix)]
+        pause_on_sigusr1(&options.paused);
+
+        let vector_check = match self.vector_check || config.vector_check == Some(true) {
+            false => None,
+            true => Some((
+                match (self.ram, config.ram) {
+                    (Some(ram), _) => ram,
+                    (None, Some(ram)) => {
+                        parse_range(&ram).context("Invalid ram in the config file")?
+                    }
+                    (None, None) => DEFAULT_RAM,
+                },
+                match (self.flash, config.flash) {
+                    (Some(flash), _) => flash,
+                    (None, Some(flash)) => {
+                        parse_range(&flash).context("Invalid flash in the config file")?
+                    }
+                    (None, None) => DEFAULT_FLASH,
+                },
+            )),
+        };
+        let start = match (self.start_at, self.start_in) {
+            (Some(at), _) if at < Utc::now() => {
+                return Err(anyhow!("--start-at {} is in the past", at.to_rfc3339()))
+            }
+            (Some(at), _) => Some(at.with_timezone(&Utc)),
+            // no delay starts right away rather than at a moment already past
+            (None, Some(delay)) if delay.is_zero() => None,
+            (None, Some(delay)) => Some(Utc::now() + delay),
+            (None, None) => None,
+        };
+        let audit_log = self
+            .audit_log
+            .or(config.audit_log)
+            .unwrap_or(PathBuf::from(audit::DEFAULT_PATH));
+        // refuse to update anything that could not be audited
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&audit_log)
+            .with_context(|| format!("Failed to open the audit log {}", audit_log.display()))?;
+
+        Ok(Settings {
+            port,
+            baudrate,
+            max_frame: self.max_frame.or(config.max_frame),
+            options,
+            vector_check,
+            pad_to: self.pad_to.or(config.pad_to),
+            align: self.align.or(config.align),
+            progress_url: self.progress_url.or(config.progress_url),
+            inventory: self
+                .inventory
+                .or(config.inventory)
+                .unwrap_or(PathBuf::from(inventory::DEFAULT_PATH)),
+            audit_log,
+            addressbook: self
+                .addressbook
+                .or(config.addressbook)
+                .unwrap_or(PathBuf::from(addressbook::DEFAULT_PATH)),
+            operator: self.operator.unwrap_or_else(audit::default_operator),
+            start,
+            connected_in: Cell::new(None),
+            verbose: self.verbose,
+        })
+    }
+}
+
+impl Settings {
+    /// The image as it is transferred, padded as configured
+    fn prepare<'a>(&self, binary: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        if let Some((ram, flash)) = &self.vector_check {
+            ota::check_vector_table(binary, ram, flash)
+                .context("Refusing the image, check --ram and --flash or turn the check off")?;
+        }
+        ota::pad(binary, self.pad_to, self.align)
+    }
+
+    fn connect(&self) -> Result<GatewayDriver> {
+        let start = Instant::now();
+        let mut gateway =
+            GatewayDriver::open(&self.port, self.baudrate).context("Failed to open port")?;
+        if let Some(max) = self.max_frame {
+            gateway.set_max_frame(max)?;
+        }
+        gateway.ping().context("Failed to connect to Gateway")?;
+        self.connected_in.set(Some(start.elapsed()));
+        Ok(gateway)
+    }
+
+    /// Waits for the scheduled start, pinging the gateway every minute so a gateway that
+    /// went away is reported before the maintenance window rather than in it. A lost ping is
+    /// retried, only a gateway that stays silent through [`WAIT_PING_RETRY`] ends the wait.
+    fn wait_for_start(&self, gateway: &mut GatewayDriver) -> Result<()> {
+        let Some(start) = self.start else {
+            return Ok(());
+        };
+        info!(
+            "Everything is ready, starting the transfer at {}",
+            start.to_rfc3339()
+        );
+        while let Ok(remaining) = (start - Utc::now()).to_std() {
+            sleep(remaining.min(Duration::from_secs(60)));
+            let (pinged, attempts) = WAIT_PING_RETRY.run(|_| {
+                gateway
+                    .ping()
+                    .inspect_err(|e| warn!("Ping while waiting to start failed: {:#}", e))
+            });
+            pinged.with_context(|| {
+                format!(
+                    "The gateway stopped answering while waiting to start, {} pings failed",
+                    attempts
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    fn publisher(&self, destination_address: usize) -> Option<Publisher> {
+        self.progress_url
+            .as_ref()
+            .map(|url| Publisher::new(url.expose().to_owned(), destination_address))
+    }
+
+    /// Updates one node, at `fallback` when it does not answer at its own address, appends
+    /// the attempt to the audit log and records a success in the inventory, failing
+    /// bookkeeping is logged but does not fail the update itself
+    fn update_node(
+        &self,
+        gateway: &mut GatewayDriver,
+        node: usize,
+        fallback: Option<usize>,
+        binary: &[u8],
+        checksum: &[u8; 32],
+        debug_log: &mut dyn ota::Observer,
+    ) -> Result<()> {
+        let mut stats = audit::Stats::default();
+        let mut phases = Phases::new();
+        if let Some(took) = self.connected_in.take() {
+            phases.record(Phase::Connect, took);
+        }
+        let start = Instant::now();
+        let display = term::transfer(self.options.block_size, self.verbose);
+        let mut observer = (
+            (((debug_log, self.publisher(node)), &mut stats), &mut phases),
+            display,
+        );
+        let mut result = ota::update(gateway, node, binary, &self.options, &mut observer);
+        if let (Err(e), Some(fallback)) = (&result, fallback) {
+            if e.downcast_ref::<ota::InitTimeout>().is_some() {
+                warn!(
+                    "Node {} did not answer, retrying at its fallback address {}",
+                    node, fallback
+                );
+                result = ota::update(gateway, fallback, binary, &self.options, &mut observer)
+                    .with_context(|| {
+                        format!(
+                            "Node {} did not answer at its fallback address {} either",
+                            node, fallback
+                        )
+                    });
+            }
+        }
+
+        let title = match result {
+            Ok(()) => format!("Node {} updated", node),
+            Err(_) => format!("Node {} was not updated", node),
+        };
+        phases.print(&title, &result);
+
+        let entry = audit::Entry::new(
+            &self.operator,
+            node,
+            &inventory::hex(checksum),
+            &result,
+            start.elapsed(),
+            &stats,
+        );
+        if let Err(e) = audit::append(&self.audit_log, &entry) {
+            error!(
+                "Node {}: the update attempt is missing from the audit log: {:#}",
+                node, e
+            );
+        }
+        if result.is_ok() {
+            let recorded = Inventory::load(&self.inventory).and_then(|mut inventory| {
+                inventory.record_update(node, checksum);
+                inventory.save()
+            });
+            if let Err(e) = recorded {
+                warn!("Failed to record node {} in the inventory: {:#}", node, e);
+            }
+        }
+        result
+    }
+}
+
+/// The address book given on the command line, in the config file or the default one
+fn load_addressbook(flag: Option<PathBuf>, config: Option<PathBuf>) -> Result<AddressBook> {
+    AddressBook::load(
+        &flag
+            .or(config)
+            .unwrap_or(PathBuf::from(addressbook::DEFAULT_PATH)),
+    )
+}
+
+/// Sizes are given in bytes, flash addresses tend to be written in hex
+fn parse_size(s: &str) -> Result<usize, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+#[cfg(unix)]
+static PAUSED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+/// `kill -USR1 <pid>` pauses the transfer and a second one resumes it
+#[cfg(unix)]
+fn pause_on_sigusr1(paused: &Arc<AtomicBool>) {
+    extern "C" fn toggle(_: libc::c_int) {
+        if let Some(paused) = PAUSED.get() {
+            paused.fetch_xor(true, Ordering::Relaxed);
+        }
+    }
+    if PAUSED.set(paused.clone()).is_ok() {
+        // Safety: the handler only touches an atomic
+        unsafe { libc::signal(libc::SIGUSR1, toggle as *const () as libc::sighandler_t) };
+    }
+}
+
+/// Durations like `90s`, `15m` or `1h30m`
+fn parse_duration(s: &str) -> Result<Duration> {
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(anyhow!("empty duration"));
+    }
+    let mut seconds = 0u64;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let value: u64 = rest[..digits]
+            .parse()
+            .map_err(|_| anyhow!("invalid duration {:?}", s))?;
+        let unit = match rest[digits..].chars().next() {
+            Some('h') => 3600,
+            Some('m') => 60,
+            Some('s') => 1,
+            _ => return Err(anyhow!("duration {:?} needs an h, m or s unit", s)),
+        };
+        seconds = value
+            .checked_mul(unit)
+            .and_then(|value| seconds.checked_add(value))
+            .ok_or(anyhow!("duration {:?} out of range", s))?;
+        rest = &rest[digits + 1..];
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+/// `start..end`, each decimal or 0x hex
+fn parse_range(s: &str) -> Result<Range<u32>> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or(anyhow!("expected start..end, got {:?}", s))?;
+    let parse = |n: &str| -> Result<u32> {
+        let n = parse_size(n).with_context(|| format!("invalid address {:?}", n))?;
+        Ok(u32::try_from(n)?)
+    };
+    let range = parse(start)?..parse(end)?;
+    match range.is_empty() {
+        true => Err(anyhow!("the range {:?} is empty", s)),
+        false => Ok(range),
+    }
+}
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    logging::init(&args.log)?;
+    match args.command {
+        Command::Update(args) => update(args),
+        Command::Campaign { plan, transfer } => {
+            let result = transfer
+                .resolve()
+                .and_then(|settings| campaign::run(&plan, &settings));
+            metrics::log_report();
+            result
+        }
+        Command::Inventory {
+            config,
+            inventory,
+            action,
+        } => {
+            let path = match inventory {
+                Some(path) => path,
+                None => Config::load(config.as_deref())?
+                    .inventory
+                    .unwrap_or(PathBuf::from(inventory::DEFAULT_PATH)),
+            };
+            show_inventory(&path, action)
+        }
+        Command::History {
+            config,
+            audit_log,
+            addressbook,
+            node,
+            limit,
+        } => {
+            let config = Config::load(config.as_deref())?;
+            let path = audit_log
+                .or(config.audit_log)
+                .unwrap_or(PathBuf::from(audit::DEFAULT_PATH));
+            let node = match node {
+                Some(node) => {
+                    Some(load_addressbook(addressbook, config.addressbook)?.address(&node)?)
+                }
+                None => None,
+            };
+            audit::print_history(&path, node, limit)
+        }
+        Command::Diff {
+            binary,
+            target,
+            config,
+            inventory,
+            pad_to,
+            align,
+            addressbook,
+        } => {
+            let config = Config::load(config.as_deref())?;
+            let inventory = Inventory::load(
+                &inventory
+                    .or(config.inventory)
+                    .unwrap_or(PathBuf::from(inventory::DEFAULT_PATH)),
+            )?;
+            let book = load_addressbook(addressbook, config.addressbook)?;
+            let mapped = ota::map_binary(&binary)?;
+            // hash the image as update sends it, padding included
+            let image = ota::pad(&mapped, pad_to.or(config.pad_to), align.or(config.align))?;
+            diff(
+                &inventory,
+                &book,
+                &target,
+                &inventory::hex(&ota::checksum(&image)),
+            )
+        }
+        Command::Addressbook {
+            config,
+            addressbook,
+            action,
+        } => {
+            let config = Config::load(config.as_deref())?;
+            load_addressbook(addressbook, config.addressbook)?.manage(action)
+        }
+        Command::Schema => schema::print(BLOCK_SIZE),
+    }
+}
+
+fn update(args: UpdateArgs) -> Result<()> {
     /* let args = Args {
         port: "/dev/ttyACM0".to_owned(),
         binary: "Cargo.toml".to_owned(),
@@ -49,145 +785,192 @@ fn main() -> Result<()> {
         return Err(anyhow!("\"{}\" is not a file", binary_path.display()));
     }
 
-    let mut debug_path = match args.debug_file {
-        Some(path) => Some(File::create(Path::new(path.as_str()))?),
-        None => None
-    };
-
-    let mut gateway =
-        GatewayDriver::new(&args.port, args.baudrate).context("Failed to open port")?;
-    gateway.ping().context("Failed to connect to Gateway")?;
-
-    let binary = std::fs::read(binary_path)?;
-    let binary_checksum = {
-        let mut c = digest::Context::new(&digest::SHA256);
-        let mut ret = [0u8; 32];
-        c.update(&binary);
-        ret.copy_from_slice(c.finish().as_ref());
-        ret
+    let settings = args.transfer.resolve()?;
+    // only a tag needs the inventory, a broken one must not stop updating a single node
+    let inventory = match Inventory::load(&settings.inventory) {
+        Err(e) if !matches!(args.target, Target::Tag(_)) => {
+            warn!(
+                "Updating without the fallback addresses in the inventory: {:#}",
+                e
+            );
+            Inventory::empty(&settings.inventory)
+        }
+        loaded => loaded?,
     };
-    let block_size = 64;
-    let index_count = {
-        if binary.len() % block_size == 0 {
-            binary.len() / block_size
-        } else {
-            binary.len() / block_size + 1
+    let nodes = inventory.resolve(&args.target, &AddressBook::load(&settings.addressbook)?)?;
+    if args.fallback_address.is_some() && nodes.len() != 1 {
+        return Err(anyhow!(
+            "--fallback-address needs a single node, {} has {}",
+            args.target,
+            nodes.len()
+        ));
+    }
+    let mut debug_log = match (args.debug_file, nodes.len()) {
+        (Some(path), 1) => Some(ota::debug_log(Path::new(path.as_str()))?),
+        (Some(_), _) => {
+            return Err(anyhow!(
+                "--debug-file needs a single node, {} has {}",
+                args.target,
+                nodes.len()
+            ))
         }
+        (None, _) => None,
     };
 
-    gateway.write(HostPacket::OtaGetStatus)?;
-    match gateway.read_with_timeout(RESPONSE_TIMEOUT)? {
-        GatewayPacket::OtaStatus(s) => {
-            if s.in_progress {
-                eprintln!("Aborting previously started update");
-                gateway.write(HostPacket::OtaAbortRequest)?;
-                match gateway.read_with_timeout(INIT_TIMEOUT)? {
-                    GatewayPacket::OtaAbortAck => {}
-                    p => {
-                        return Err(anyhow!("failed to abort the OTA update: {:?}", p));
-                    }
-                }
-            }
+    let mapped = ota::map_binary(&binary_path)?;
+    let binary = settings.prepare(&mapped)?;
+    let checksum = ota::checksum(&binary);
+    let mut gateway = settings.connect()?;
+    settings.wait_for_start(&mut gateway)?;
+    let mut failed = Vec::new();
+    for &node in &nodes {
+        if nodes.len() > 1 {
+            info!("Updating node {}", node);
         }
-        p => {
-            return Err(anyhow!("failed to initialize the OTA update: {:?}", p));
-        }
-    }
-
-    eprintln!("Initializing the peer update with {} blocks of size {}, {}B total", index_count, block_size, binary.len());
-    gateway.write(HostPacket::OtaInit(OtaInitRequest {
-        destination_address: args.destination_address,
-        binary_size: binary.len() as u32,
-        binary_sha256: binary_checksum,
-        block_size: block_size as u16,
-        block_count: index_count as u16,
-    }))?;
-    match gateway.read_with_timeout(INIT_TIMEOUT)? {
-        GatewayPacket::OtaInitAck => { /* update started */ }
-        p => {
-            return Err(anyhow!("failed to initialize the OTA update: {:?}", p));
-        }
-    }
-
-    let mut indexes_to_transmit: Vec<u16> = Vec::with_capacity(index_count);
-    let mut highest_index: u16 = 0;
-    let mut last_acked_index: u16 = 0;
-    let mut transmitted_count = 0;
-    let update_start_time = Instant::now();
-
-    if let Some(f) = debug_path.as_mut() {
-        f.write_all("time,txed,acked\n".as_bytes())?;
-    }
-
-    loop {
-        if indexes_to_transmit.is_empty() && highest_index == index_count as u16 {
-            eprintln!("Requesting ota done status");
-            gateway.write(HostPacket::OtaDoneRequest)?;
-        } else {
-            let i = match indexes_to_transmit.pop() {
-                Some(i) => i as usize,
-                None => {
-                    let tmp = highest_index;
-                    if last_acked_index + 12 >= highest_index {
-                        highest_index += 1;
-                    } else {
-                        eprint!("not advancing further, last acked {}, highest {}", last_acked_index, highest_index);
-                    }
-                    tmp as usize
-                }
-            };
-            let begin = i * block_size;
-            let end = {
-                if (i + 1) * block_size >= binary.len() {
-                    binary.len() - 1
-                } else {
-                    (i + 1) * block_size
-                }
-            };
-            eprintln!("Transmitting block {}", i);
-            transmitted_count += 1;
-            gateway.write(HostPacket::OtaData(OtaData {
-                index: i as u16,
-                data: binary[begin..end].iter().cloned().collect(),
-            }))?;
-        }
-
-        match gateway.read_with_timeout(RESPONSE_TIMEOUT) {
-            Ok(packet) => match packet {
-                GatewayPacket::OtaStatus(status) => {
-                    for na in status.not_acked {
-                        if !indexes_to_transmit.contains(&na) {
-                            eprintln!(
-                                "Scheduling {} to retransmit along with {:?}",
-                                na, indexes_to_transmit
-                            );
-                            indexes_to_transmit.push(na);
-                        }
-                    }
-                    last_acked_index = status.last_acked;
-                    sleep(Duration::from_millis(150));
-                }
-                GatewayPacket::OtaDoneAck => {
-                    println!("done");
-                    break;
-                }
-                resp => {
-                    eprintln!("Unexpected response from gateway during OTA: {:?}", resp);
-                }
-            },
+        let fallback = args.fallback_address.or(inventory.fallback_address(node));
+        match settings.update_node(
+            &mut gateway,
+            node,
+            fallback,
+            &binary,
+            &checksum,
+            &mut debug_log,
+        ) {
+            Ok(()) => {}
+            Err(e) if nodes.len() == 1 => {
+                metrics::log_report();
+                return Err(e);
+            }
             Err(e) => {
-                eprintln!("Error during read: {}", e);
+                warn!("Node {}: update failed: {:#}", node, e);
+                failed.push(node);
             }
         }
+    }
+    metrics::log_report();
+    match failed.is_empty() {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "Failed to update nodes {:?} of {}",
+            failed,
+            args.target
+        )),
+    }
+}
 
-        if let Some(f) = debug_path.as_mut() {
-            f.write_all(format!("{},{},{}\n", update_start_time.elapsed().as_secs(), transmitted_count, last_acked_index).as_bytes())?;
+/// Prints whether each node is recorded as running the image, for scripts updating only
+/// the nodes that need it
+fn diff(
+    inventory: &Inventory,
+    book: &AddressBook,
+    target: &Target,
+    image_sha256: &str,
+) -> Result<()> {
+    let mut stale = 0;
+    for node in inventory.resolve(target, book)? {
+        let status = match inventory
+            .nodes
+            .get(&node)
+            .and_then(|n| n.firmware_sha256.as_deref())
+        {
+            Some(sha256) if sha256 == image_sha256 => ("identical", Color::Green),
+            Some(_) => ("different", Color::Yellow),
+            None => ("unknown", Color::Dim),
+        };
+        if status.0 != "identical" {
+            stale += 1;
         }
+        println!("{} {}", node, term::paint(status.0, status.1));
+    }
+    match stale {
+        0 => Ok(()),
+        _ => Err(anyhow!("{} nodes may not be running the image", stale)),
     }
+}
 
-    if let Some(f) = debug_path.as_mut() {
-        f.write_all(format!("{},{},{}\n", update_start_time.elapsed().as_secs(), transmitted_count, index_count).as_bytes())?;
+fn show_inventory(path: &Path, action: Option<InventoryAction>) -> Result<()> {
+    let mut inventory = Inventory::load(path)?;
+    match action {
+        Some(InventoryAction::Set {
+            address,
+            name,
+            hardware_rev,
+            fallback_address,
+            tag,
+            untag,
+        }) => {
+            let node = inventory.node_mut(address);
+            node.tags.extend(tag);
+            for tag in untag {
+                node.tags.remove(&tag);
+            }
+            if name.is_some() {
+                node.name = name;
+            }
+            if hardware_rev.is_some() {
+                node.hardware_rev = hardware_rev;
+            }
+            if fallback_address.is_some() {
+                node.fallback_address = fallback_address;
+            }
+            return inventory.save();
+        }
+        Some(InventoryAction::Remove { address }) => {
+            if inventory.nodes.remove(&address).is_none() {
+                return Err(anyhow!("Node {} is not in the inventory", address));
+            }
+            return inventory.save();
+        }
+        None => {}
     }
 
+    println!(
+        "{:>8}  {:<16} {:<8} {:<12} {:<26} TAGS",
+        "ADDRESS", "NAME", "HW REV", "FIRMWARE", "LAST CONTACT"
+    );
+    for (address, node) in &inventory.nodes {
+        println!(
+            "{:>8}  {:<16} {:<8} {:<12} {:<26} {}",
+            address,
+            node.name.as_deref().unwrap_or("-"),
+            node.hardware_rev.as_deref().unwrap_or("-"),
+            node.firmware_sha256
+                .as_deref()
+                .map_or("-", |h| &h[..h.len().min(12)]),
+            node.last_contact.as_deref().unwrap_or("-"),
+            node.tags.iter().cloned().collect::<Vec<String>>().join(",")
+        );
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_add_up_their_units() {
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("0s").unwrap(), Duration::ZERO);
+        for invalid in ["", "15", "m", "1d", "1h30"] {
+            assert!(parse_duration(invalid).is_err(), "{:?}", invalid);
+        }
+        for overflowing in ["5124095576030432h", "18446744073709551615s1s"] {
+            let error = parse_duration(overflowing).unwrap_err().to_string();
+            assert!(error.contains("out of range"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn ranges_take_decimal_and_hex() {
+        assert_eq!(
+            parse_range("0x08000000..0x08040000").unwrap(),
+            DEFAULT_FLASH
+        );
+        assert_eq!(parse_range("16..32").unwrap(), 16..32);
+        for invalid in ["16", "32..16", "16..16", "0x100000000..0x100000001", "a..b"] {
+            assert!(parse_range(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use lora_host_common::ota::{Observer, Progress};
+use serde_json::{json, Value};
+use std::{
+    sync::mpsc::{self, SyncSender, TrySendError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+const MIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Posts progress events to a dashboard endpoint, at most one every few seconds. The posts
+/// go out from a thread of their own so the transfer is not slowed down by a slow endpoint,
+/// events it cannot keep up with are dropped.
+pub struct Publisher {
+    events: Option<SyncSender<Value>>,
+    poster: Option<JoinHandle<()>>,
+    destination_address: usize,
+    last_sent: Option<Instant>,
+}
+
+impl Publisher {
+    pub fn new(url: String, destination_address: usize) -> Self {
+        let (events, received) = mpsc::sync_channel(1);
+        let poster = thread::spawn(move || post(&url, received));
+        Self {
+            events: Some(events),
+            poster: Some(poster),
+            destination_address,
+            last_sent: None,
+        }
+    }
+
+    fn publish(&mut self, progress: &Progress) {
+        if !progress.done && self.last_sent.is_some_and(|t| t.elapsed() < MIN_INTERVAL) {
+            return;
+        }
+        self.last_sent = Some(Instant::now());
+
+        let event = json!({
+            "node": self.destination_address,
+            "percent": progress.percent(),
+            "blocks_acked": progress.acked_blocks(),
+            "block_count": progress.block_count,
+            "blocks_sent": progress.sent,
+            "retransmit_rate": progress.retransmit_rate(),
+            "elapsed_s": progress.elapsed.as_secs(),
+            "eta_s": progress.eta().map(|eta| eta.as_secs()),
+            "done": progress.done,
+        });
+        let Some(events) = &self.events else { return };
+        // the final event is worth waiting for the one in flight
+        let sent = match progress.done {
+            true => events.send(event).is_ok(),
+            false => !matches!(events.try_send(event), Err(TrySendError::Disconnected(_))),
+        };
+        if !sent {
+            self.events = None;
+        }
+    }
+}
+
+/// Delivers events until the publisher is dropped, failures are logged once, an unreachable
+/// dashboard must not stop the update
+fn post(url: &str, events: mpsc::Receiver<Value>) {
+    let client = reqwest::blocking::Client::new();
+    let mut failing = false;
+    for event in events {
+        let result = client
+            .post(url)
+            .timeout(Duration::from_secs(2))
+            .json(&event)
+            .send()
+            .and_then(|r| r.error_for_status());
+        match result {
+            Err(e) if !failing => {
+                warn!("Failed to publish progress: {}", e.without_url());
+                failing = true;
+            }
+            Err(_) => {}
+            Ok(_) => failing = false,
+        }
+    }
+}
+
+/// Waits for the last event to be delivered
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        self.events = None;
+        if let Some(poster) = self.poster.take() {
+            let _ = poster.join();
+        }
+    }
+}
+
+impl Observer for Publisher {
+    fn on_ack(&mut self, progress: &Progress) {
+        self.publish(progress);
+    }
+    fn on_complete(&mut self, progress: &Progress) {
+        self.publish(progress);
+    }
+}
@@ -0,0 +1,510 @@
+use crate::{connect_error, soil::ZoneSettings, HTTP_RETRY};
+use anyhow::{anyhow, Context, Result};
+use chrono::prelude::*;
+use chrono_tz::Tz;
+use lora_host_common::{metrics, secret::Secret};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OpenSprinklerConfig {
+    /// Base URL of the controller, e.g. `http://192.168.1.50`
+    pub url: String,
+    /// MD5 hash of the device password, as the OpenSprinkler API expects it
+    pub password_md5: Secret,
+    /// How long a station runs once watering is decided, unless its zone sets a cycle length
+    pub run_minutes: u32,
+    pub stations: Vec<StationMapping>,
+    /// Seconds between closing one valve and opening the next, stations run one at a time when
+    /// set, against water hammer
+    #[serde(default)]
+    pub transition_delay_secs: u64,
+}
+
+/// Assigns a zone of a sensor node to an OpenSprinkler station
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StationMapping {
+    pub node: usize,
+    pub zone: String,
+    /// Zero based station index
+    pub station: u32,
+    /// Minutes the station may run per ISO week. A run longer than what is left of it, the
+    /// zone's cycle or one scaled up by the forecast, is shortened to the rest of the budget
+    #[serde(default)]
+    pub weekly_budget_minutes: Option<u32>,
+}
+
+impl StationMapping {
+    fn key(&self) -> String {
+        format!("{}/{}", self.node, self.zone)
+    }
+}
+
+/// Minutes watered per zone in the current week, runs started today and the stations' continuous
+/// run time, persisted so restarts do not reset the limits
+#[derive(Default, Serialize, Deserialize)]
+struct WaterUsage {
+    week: String,
+    minutes: HashMap<String, u32>,
+    #[serde(default)]
+    day: String,
+    #[serde(default)]
+    runs: HashMap<String, u32>,
+    /// Stations started and not seen closed yet
+    #[serde(default)]
+    open: HashMap<u32, OpenRun>,
+    /// Minutes each station was open since its node started asking for water, without the run
+    /// still open
+    #[serde(default)]
+    continuous: HashMap<u32, u32>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct OpenRun {
+    since: DateTime<Utc>,
+    /// When the controller closes the station on its own
+    until: DateTime<Utc>,
+}
+
+/// A station closed for watering longer than its zone allows in one go
+pub struct Cutoff {
+    pub station: u32,
+    pub zone: String,
+    pub minutes: u32,
+}
+
+/// Drives stations of an OpenSprinkler controller through its HTTP API
+pub struct OpenSprinkler {
+    config: OpenSprinklerConfig,
+    zones: HashMap<String, ZoneSettings>,
+    next_start: HashMap<u32, Instant>,
+    /// Stations reported as cut off, until their node stops asking for water
+    cut_off: HashSet<u32>,
+    /// When the last started station closes plus the transition delay
+    busy_until: Instant,
+    usage_path: PathBuf,
+    usage: WaterUsage,
+}
+
+impl OpenSprinkler {
+    pub fn new(
+        config: OpenSprinklerConfig,
+        zones: HashMap<String, ZoneSettings>,
+        usage_path: PathBuf,
+    ) -> Self {
+        let usage = std::fs::read_to_string(&usage_path)
+            .ok()
+            .and_then(|u| serde_json::from_str(&u).ok())
+            .unwrap_or_default();
+        Self {
+            config,
+            zones,
+            next_start: HashMap::new(),
+            cut_off: HashSet::new(),
+            busy_until: Instant::now(),
+            usage_path,
+            usage,
+        }
+    }
+
+    fn roll_over(&mut self, now: &DateTime<Tz>) {
+        let week = format!("{}-W{:02}", now.iso_week().year(), now.iso_week().week());
+        if self.usage.week != week {
+            self.usage.week = week;
+            self.usage.minutes.clear();
+        }
+        let day = now.date_naive().to_string();
+        if self.usage.day != day {
+            self.usage.day = day;
+            self.usage.runs.clear();
+        }
+    }
+
+    fn zone(&self, mapping: &StationMapping) -> Option<&ZoneSettings> {
+        self.zones.get(&mapping.zone)
+    }
+
+    fn run_minutes(&self, mapping: &StationMapping) -> u32 {
+        self.zone(mapping)
+            .and_then(|z| z.cycle_minutes())
+            .unwrap_or(self.config.run_minutes)
+    }
+
+    /// Minutes left of the station's weekly budget, None without a budget
+    fn budget_left(&self, mapping: &StationMapping) -> Option<u32> {
+        let used = self.usage.minutes.get(&mapping.key()).cloned().unwrap_or(0);
+        mapping
+            .weekly_budget_minutes
+            .map(|budget| budget.saturating_sub(used))
+    }
+
+    fn within_budget(&self, mapping: &StationMapping) -> bool {
+        self.budget_left(mapping) != Some(0)
+    }
+
+    /// The station's run scaled by `factor` and shortened to what is left of its weekly budget
+    fn budgeted_minutes(&self, mapping: &StationMapping, factor: f64) -> u32 {
+        let scaled = (self.run_minutes(mapping) as f64 * factor).round() as u32;
+        match self.budget_left(mapping) {
+            Some(left) => scaled.min(left),
+            None => scaled,
+        }
+    }
+
+    fn within_run_limit(&self, mapping: &StationMapping) -> bool {
+        let runs = self.usage.runs.get(&mapping.key()).cloned().unwrap_or(0);
+        match self.zone(mapping).and_then(|z| z.max_runs_per_day()) {
+            Some(limit) => runs < limit,
+            None => true,
+        }
+    }
+
+    fn all_stations(
+        &mut self,
+        node: usize,
+        now: &DateTime<Tz>,
+        f: impl Fn(&Self, &StationMapping) -> bool,
+    ) -> bool {
+        self.roll_over(now);
+        let mut mappings = self
+            .config
+            .stations
+            .iter()
+            .filter(|m| m.node == node)
+            .peekable();
+        mappings.peek().is_some() && mappings.all(|m| f(self, m))
+    }
+
+    /// Whether the node has mapped stations and every one of them used up its weekly budget
+    pub fn budget_exhausted(&mut self, node: usize, now: &DateTime<Tz>) -> bool {
+        self.all_stations(node, now, |s, m| !s.within_budget(m))
+    }
+
+    /// Logs the minutes each budgeted zone used this week next to the metrics report, and how
+    /// many zones have no budget left as a gauge
+    pub fn log_budgets(&mut self, now: &DateTime<Tz>) {
+        self.roll_over(now);
+        let budgets = self
+            .config
+            .stations
+            .iter()
+            .filter_map(|m| {
+                m.weekly_budget_minutes
+                    .map(|b| (m.key(), (b, self.within_budget(m))))
+            })
+            .collect::<BTreeMap<String, (u32, bool)>>();
+        let exhausted = budgets.values().filter(|(_, within)| !within).count();
+        metrics::gauge("sprinkler.budgets_exhausted", exhausted as f64);
+        for (key, (budget, _)) in budgets {
+            let used = self.usage.minutes.get(&key).cloned().unwrap_or(0);
+            info!(
+                target: "metrics",
+                "sprinkler.budget {} used {} of {} minutes in {}",
+                key,
+                used,
+                budget,
+                self.usage.week
+            );
+        }
+    }
+
+    /// Whether the node has mapped stations and every one of them ran as often as allowed today
+    pub fn runs_exhausted(&mut self, node: usize, now: &DateTime<Tz>) -> bool {
+        self.all_stations(node, now, |s, m| !s.within_run_limit(m))
+    }
+
+    fn transition_delay(&self) -> Duration {
+        Duration::from_secs(self.config.transition_delay_secs)
+    }
+
+    fn save_usage(&self) -> Result<()> {
+        std::fs::write(&self.usage_path, serde_json::to_string(&self.usage)?)
+            .context("Failed to save the water usage")
+    }
+
+    /// Minutes the station was open since its node started asking for water, up to `now`
+    fn open_minutes(&self, station: u32, now: DateTime<Utc>) -> u32 {
+        let closed = self.usage.continuous.get(&station).cloned().unwrap_or(0);
+        let open = self.usage.open.get(&station).map_or(0, |run| {
+            (now.min(run.until) - run.since).num_minutes().max(0) as u32
+        });
+        closed + open
+    }
+
+    /// Adds the station's open run to its continuous run time once it is closed
+    fn closed(&mut self, station: u32, now: DateTime<Utc>) {
+        if self.usage.open.contains_key(&station) {
+            let minutes = self.open_minutes(station, now);
+            self.usage.open.remove(&station);
+            self.usage.continuous.insert(station, minutes);
+        }
+    }
+
+    /// Resets the continuous run time of the node's stations once it no longer asks for water,
+    /// stations still open keep counting until they are closed
+    pub fn idle(&mut self, node: usize) -> Result<()> {
+        let mut reset = false;
+        for mapping in self.config.stations.iter().filter(|m| m.node == node) {
+            if self.usage.open.contains_key(&mapping.station) {
+                continue;
+            }
+            self.cut_off.remove(&mapping.station);
+            reset |= self.usage.continuous.remove(&mapping.station).is_some();
+        }
+        match reset {
+            true => self.save_usage(),
+            false => Ok(()),
+        }
+    }
+
+    /// Checks the node's open stations whatever it decided: stations open for as long as their
+    /// zone's continuous limit allows are closed and returned, those whose run is over are
+    /// closed again in case the controller missed it
+    pub fn enforce(&mut self, node: usize, now: &DateTime<Tz>) -> Result<Vec<Cutoff>> {
+        let now = now.with_timezone(&Utc);
+        let mappings = self
+            .config
+            .stations
+            .iter()
+            .filter(|m| m.node == node)
+            .cloned()
+            .collect::<Vec<StationMapping>>();
+
+        let mut cutoffs = Vec::new();
+        for mapping in mappings {
+            let Some(run) = self.usage.open.get(&mapping.station).copied() else {
+                continue;
+            };
+            let minutes = self.open_minutes(mapping.station, now);
+            let limit = self.zone(&mapping).and_then(|z| z.max_continuous_minutes);
+            let over = now < run.until && limit.is_some_and(|limit| minutes >= limit);
+            if !over && now < run.until {
+                continue;
+            }
+            self.stop_station(mapping.station)?;
+            self.closed(mapping.station, now);
+            self.save_usage()?;
+            if over {
+                self.busy_until = Instant::now() + self.transition_delay();
+                self.cut_off.insert(mapping.station);
+                cutoffs.push(Cutoff {
+                    station: mapping.station,
+                    zone: mapping.zone.clone(),
+                    minutes,
+                });
+            }
+        }
+        Ok(cutoffs)
+    }
+
+    /// Starts a timed run on every station mapped to the node's zones. Stations still open or
+    /// soaking after a previous run are left alone so that repeated polls do not restart them,
+    /// and stations without weekly budget or runs left today are skipped. Runs are scaled by
+    /// `factor` and shortened to the remaining budget and the zone's continuous limit, stations
+    /// that used up the limit are returned once.
+    /// With a transition delay stations wait for the previous one to close, a later poll starts
+    /// them.
+    pub fn water(&mut self, node: usize, now: &DateTime<Tz>, factor: f64) -> Result<Vec<Cutoff>> {
+        self.roll_over(now);
+        let mappings = self
+            .config
+            .stations
+            .iter()
+            .filter(|m| m.node == node && self.within_budget(m) && self.within_run_limit(m))
+            .cloned()
+            .collect::<Vec<StationMapping>>();
+
+        let mut cutoffs = Vec::new();
+        for mapping in mappings {
+            if self.cut_off.contains(&mapping.station)
+                || self.usage.open.contains_key(&mapping.station)
+            {
+                continue;
+            }
+            let ran = self
+                .usage
+                .continuous
+                .get(&mapping.station)
+                .cloned()
+                .unwrap_or(0);
+            let limit = self.zone(&mapping).and_then(|z| z.max_continuous_minutes);
+            if limit.is_some_and(|limit| ran >= limit) {
+                self.cut_off.insert(mapping.station);
+                cutoffs.push(Cutoff {
+                    station: mapping.station,
+                    zone: mapping.zone.clone(),
+                    minutes: ran,
+                });
+                continue;
+            }
+            if self
+                .next_start
+                .get(&mapping.station)
+                .is_some_and(|next| *next > Instant::now())
+            {
+                continue;
+            }
+            if self.config.transition_delay_secs > 0 && self.busy_until > Instant::now() {
+                continue;
+            }
+            let scaled = self.budgeted_minutes(&mapping, factor);
+            let minutes = match limit {
+                Some(limit) => scaled.min(limit - ran),
+                None => scaled,
+            };
+            if minutes == 0 {
+                continue;
+            }
+            let soak = self
+                .zone(&mapping)
+                .and_then(|z| z.soak_minutes())
+                .unwrap_or(0);
+            let run = Duration::from_secs(minutes as u64 * 60);
+            self.run_station(mapping.station, run)?;
+            self.busy_until = Instant::now() + run + self.transition_delay();
+            self.next_start.insert(
+                mapping.station,
+                Instant::now() + run + Duration::from_secs(soak as u64 * 60),
+            );
+            let since = now.with_timezone(&Utc);
+            let until = since + chrono::Duration::minutes(minutes as i64);
+            self.usage
+                .open
+                .insert(mapping.station, OpenRun { since, until });
+            *self.usage.minutes.entry(mapping.key()).or_default() += minutes;
+            *self.usage.runs.entry(mapping.key()).or_default() += 1;
+            self.save_usage()?;
+        }
+        Ok(cutoffs)
+    }
+
+    /// Closes every mapped station, waiting the transition delay between two of them
+    pub fn close_all(&mut self) -> Result<()> {
+        let mut stations = self
+            .config
+            .stations
+            .iter()
+            .map(|m| m.station)
+            .collect::<Vec<u32>>();
+        stations.sort();
+        stations.dedup();
+        for (i, station) in stations.into_iter().enumerate() {
+            if i > 0 {
+                std::thread::sleep(self.transition_delay());
+            }
+            self.stop_station(station)?;
+            self.closed(station, Utc::now());
+        }
+        self.next_start.clear();
+        self.busy_until = Instant::now();
+        self.save_usage()
+    }
+
+    fn run_station(&self, station: u32, run: Duration) -> Result<()> {
+        self.control(station, &format!("en=1&t={}", run.as_secs()))
+    }
+
+    fn stop_station(&self, station: u32) -> Result<()> {
+        self.control(station, "en=0")
+    }
+
+    fn control(&self, station: u32, command: &str) -> Result<()> {
+        let url = format!(
+            "{}/cm?pw={}&sid={}&{}",
+            self.config.url.trim_end_matches('/'),
+            self.config.password_md5.expose(),
+            station,
+            command
+        );
+        let (response, _) = HTTP_RETRY.run_if(connect_error, |_| {
+            Ok(reqwest::blocking::get(&url)
+                .and_then(|r| r.json::<serde_json::Value>())
+                .map_err(reqwest::Error::without_url)?)
+        });
+        let response = response?;
+        match response["result"].as_i64() {
+            Some(1) => Ok(()),
+            _ => Err(anyhow!(
+                "OpenSprinkler refused to switch station {}: {}",
+                station,
+                response
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budgeted(budget: u32, used: u32) -> (OpenSprinkler, StationMapping) {
+        let mapping = StationMapping {
+            node: 1,
+            zone: "lawn".to_string(),
+            station: 0,
+            weekly_budget_minutes: Some(budget),
+        };
+        let config = OpenSprinklerConfig {
+            url: "http://localhost".to_string(),
+            password_md5: "0".parse().unwrap(),
+            run_minutes: 10,
+            stations: vec![mapping.clone()],
+            transition_delay_secs: 0,
+        };
+        let mut sprinkler = OpenSprinkler::new(config, HashMap::new(), PathBuf::new());
+        sprinkler.usage.minutes.insert(mapping.key(), used);
+        (sprinkler, mapping)
+    }
+
+    #[test]
+    fn scaled_runs_stay_within_the_budget() {
+        let (sprinkler, mapping) = budgeted(60, 55);
+        assert!(sprinkler.within_budget(&mapping));
+        assert_eq!(sprinkler.budgeted_minutes(&mapping, 1.5), 5);
+
+        let (sprinkler, mapping) = budgeted(60, 0);
+        assert_eq!(sprinkler.budgeted_minutes(&mapping, 1.5), 15);
+
+        let (sprinkler, mapping) = budgeted(60, 60);
+        assert!(!sprinkler.within_budget(&mapping));
+        assert_eq!(sprinkler.budgeted_minutes(&mapping, 1.5), 0);
+    }
+
+    #[test]
+    fn long_cycles_use_the_rest_of_the_budget() {
+        let (mut sprinkler, mapping) = budgeted(60, 50);
+        let zone = ZoneSettings {
+            cycle_minutes: Some(30),
+            ..Default::default()
+        };
+        sprinkler.zones.insert(mapping.zone.clone(), zone);
+        assert!(sprinkler.within_budget(&mapping));
+        assert_eq!(sprinkler.budgeted_minutes(&mapping, 1.0), 10);
+    }
+
+    #[test]
+    fn open_stations_keep_counting_through_idle_and_restarts() {
+        let (mut sprinkler, mapping) = budgeted(600, 0);
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let run = OpenRun {
+            since: now - chrono::Duration::minutes(20),
+            until: now + chrono::Duration::minutes(10),
+        };
+        sprinkler.usage.open.insert(mapping.station, run);
+        sprinkler.usage.continuous.insert(mapping.station, 15);
+        sprinkler.idle(mapping.node).unwrap();
+        assert_eq!(sprinkler.open_minutes(mapping.station, now), 35);
+
+        let saved = serde_json::to_string(&sprinkler.usage).unwrap();
+        sprinkler.usage = serde_json::from_str(&saved).unwrap();
+        // counted up to the end of the run, the controller closes the station then
+        let later = now + chrono::Duration::hours(1);
+        assert_eq!(sprinkler.open_minutes(mapping.station, later), 45);
+        sprinkler.closed(mapping.station, later);
+        assert!(sprinkler.usage.open.is_empty());
+        assert_eq!(sprinkler.usage.continuous[&mapping.station], 45);
+    }
+}
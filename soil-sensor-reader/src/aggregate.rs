@@ -0,0 +1,155 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::prelude::*;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const TIME_FORMAT: &str = "%y-%m-%d %H:%M.%S";
+/// Time format of the hourly log, minutes are always zero
+pub const HOURLY_TIME_FORMAT: &str = "%y-%m-%d %H:%M";
+
+#[derive(Default)]
+struct Bucket {
+    sums: Vec<f64>,
+    samples: u32,
+    water: u32,
+}
+
+/// Path of the hourly aggregate file belonging to a raw log, `sensor_log.csv` -> `sensor_log_hourly.csv`
+pub fn hourly_path(log_path: &Path) -> PathBuf {
+    let stem = log_path.file_stem().unwrap_or_default().to_string_lossy();
+    log_path.with_file_name(format!("{}_hourly.csv", stem))
+}
+
+/// Rolls raw log rows older than `cutoff` into hourly averages appended to the hourly file and
+/// rewrites the raw log with only the newer rows. Returns the number of raw rows removed.
+///
+/// The raw log is expected to be laid out as `time,<zones...>,moisture,pop,water`; rows with an
+/// unparseable timestamp are kept as they are.
+pub fn compact(log_path: &Path, cutoff: NaiveDateTime) -> Result<usize> {
+    let log = std::fs::read_to_string(log_path).context("Failed to read the log")?;
+    let mut lines = log.lines();
+    let header = lines
+        .next()
+        .ok_or(anyhow!("{} is empty", log_path.display()))?;
+    let columns = header.split(',').count();
+    if columns < 5 {
+        return Err(anyhow!("Unexpected log header \"{}\"", header));
+    }
+
+    let mut kept = vec![header];
+    let mut buckets: BTreeMap<NaiveDateTime, Bucket> = BTreeMap::new();
+    for line in lines.filter(|l| !l.is_empty()) {
+        let fields = line.split(',').collect::<Vec<&str>>();
+        let time = match NaiveDateTime::parse_from_str(fields[0], TIME_FORMAT) {
+            Ok(time) if time < cutoff && fields.len() == columns => time,
+            _ => {
+                kept.push(line);
+                continue;
+            }
+        };
+
+        let hour = time.date().and_hms_opt(time.hour(), 0, 0).unwrap();
+        let bucket = buckets.entry(hour).or_default();
+        bucket.sums.resize(columns - 2, 0.0);
+        bucket.water += (fields[columns - 1] == "1") as u32;
+        // degraded rows carry no readings, only the fail-safe decision
+        let values = fields[1..columns - 1]
+            .iter()
+            .map(|f| f.parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>();
+        if let Ok(values) = values {
+            for (sum, v) in bucket.sums.iter_mut().zip(values) {
+                *sum += v;
+            }
+            bucket.samples += 1;
+        }
+    }
+
+    if buckets.is_empty() {
+        return Ok(0);
+    }
+    let removed = log.lines().filter(|l| !l.is_empty()).count() - kept.len();
+
+    let hourly_path = hourly_path(log_path);
+    let mut hourly = match hourly_path.exists() {
+        true => OpenOptions::new()
+            .append(true)
+            .open(&hourly_path)
+            .context("Failed to open the hourly log")?,
+        false => {
+            let mut f = File::create(&hourly_path).context("Failed to create the hourly log")?;
+            f.write_all(
+                format!("hour{},samples\n", &header[header.find(',').unwrap()..]).as_bytes(),
+            )?;
+            f
+        }
+    };
+    for (hour, bucket) in buckets {
+        let values = bucket
+            .sums
+            .iter()
+            .map(|sum| match bucket.samples {
+                0 => String::new(),
+                n => format!("{}", (sum / n as f64).round()),
+            })
+            .collect::<Vec<String>>();
+        hourly.write_all(
+            format!(
+                "{},{},{},{}\n",
+                hour.format(HOURLY_TIME_FORMAT),
+                values.join(","),
+                bucket.water,
+                bucket.samples
+            )
+            .as_bytes(),
+        )?;
+    }
+
+    let tmp_path = log_path.with_extension("csv.tmp");
+    std::fs::write(&tmp_path, kept.join("\n") + "\n")
+        .context("Failed to write the compacted log")?;
+    std::fs::rename(&tmp_path, log_path).context("Failed to replace the log")?;
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_rolls_old_rows_into_hours() {
+        let dir = std::env::temp_dir().join(format!("aggregate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("sensor_log.csv");
+        std::fs::write(
+            &log,
+            "time,zone1,moisture,pop,water\n\
+             24-06-01 10:05.00,300,20,10,1\n\
+             24-06-01 10:35.00,310,30,10,0\n\
+             24-06-01 10:50.00,,,,0\n\
+             24-06-01 11:10.00,320,40,0,0\n\
+             24-06-02 10:00.00,330,50,0,0\n",
+        )
+        .unwrap();
+        let cutoff = NaiveDate::from_ymd_opt(2024, 6, 2)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        assert_eq!(compact(&log, cutoff).unwrap(), 4);
+        assert_eq!(
+            std::fs::read_to_string(hourly_path(&log)).unwrap(),
+            "hour,zone1,moisture,pop,water,samples\n\
+             24-06-01 10:00,305,25,10,1,2\n\
+             24-06-01 11:00,320,40,0,0,1\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "time,zone1,moisture,pop,water\n24-06-02 10:00.00,330,50,0,0\n"
+        );
+        // nothing left to roll up
+        assert_eq!(compact(&log, cutoff).unwrap(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
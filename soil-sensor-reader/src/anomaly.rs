@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+
+/// Bounds on the change of a zone's moisture between two polls, in percentage points
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AnomalyConfig {
+    pub max_rise: f64,
+    pub max_drop: f64,
+}
+
+pub enum Transition {
+    Flagged { from: f64, to: f64 },
+    Cleared,
+}
+
+/// Flags implausible per-zone jumps, such as a probe pulled out of the soil or a wiring fault.
+///
+/// A flagged zone is compared against its last plausible value until it comes back within
+/// bounds, so a permanently broken probe keeps being excluded rather than becoming the baseline.
+pub struct AnomalyDetector {
+    config: Option<AnomalyConfig>,
+    baseline: Vec<Option<f64>>,
+    flagged: Vec<bool>,
+}
+
+impl AnomalyDetector {
+    pub fn new(config: Option<AnomalyConfig>, zones: usize) -> Self {
+        Self {
+            config,
+            baseline: vec![None; zones],
+            flagged: vec![false; zones],
+        }
+    }
+
+    /// Feeds normalized (0..1) zone readings, returns the zones whose state changed
+    pub fn update(&mut self, zones: &[f64]) -> Vec<(usize, Transition)> {
+        let Some(config) = &self.config else {
+            return Vec::new();
+        };
+
+        let mut transitions = Vec::new();
+        for (i, m) in zones.iter().enumerate() {
+            let anomalous = match self.baseline[i] {
+                Some(last) => {
+                    let delta = (m - last) * 100.0;
+                    delta > config.max_rise || -delta > config.max_drop
+                }
+                None => false,
+            };
+            if anomalous && !self.flagged[i] {
+                transitions.push((
+                    i,
+                    Transition::Flagged {
+                        from: self.baseline[i].unwrap_or_default(),
+                        to: *m,
+                    },
+                ));
+            } else if !anomalous && self.flagged[i] {
+                transitions.push((i, Transition::Cleared));
+            }
+            self.flagged[i] = anomalous;
+            if !anomalous {
+                self.baseline[i] = Some(*m);
+            }
+        }
+        transitions
+    }
+
+    pub fn flagged(&self) -> &[bool] {
+        &self.flagged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector() -> AnomalyDetector {
+        let config = AnomalyConfig {
+            max_rise: 20.0,
+            max_drop: 10.0,
+        };
+        AnomalyDetector::new(Some(config), 2)
+    }
+
+    #[test]
+    fn jumps_are_flagged_until_back_in_bounds() {
+        let mut detector = detector();
+        assert!(detector.update(&[0.3, 0.3]).is_empty());
+        let transitions = detector.update(&[0.55, 0.25]);
+        assert!(matches!(
+            transitions[..],
+            [(0, Transition::Flagged { from, to })] if from == 0.3 && to == 0.55
+        ));
+        assert_eq!(detector.flagged(), [true, false]);
+        // still compared against the last plausible reading, not the jump
+        assert!(detector.update(&[0.6, 0.25]).is_empty());
+        let transitions = detector.update(&[0.45, 0.2]);
+        assert!(matches!(transitions[..], [(0, Transition::Cleared)]));
+        assert_eq!(detector.flagged(), [false, false]);
+    }
+
+    #[test]
+    fn drops_have_their_own_bound() {
+        let mut detector = detector();
+        detector.update(&[0.5, 0.5]);
+        detector.update(&[0.65, 0.35]);
+        assert_eq!(detector.flagged(), [false, true]);
+    }
+
+    #[test]
+    fn nothing_is_flagged_without_bounds() {
+        let mut detector = AnomalyDetector::new(None, 1);
+        detector.update(&[0.0]);
+        assert!(detector.update(&[1.0]).is_empty());
+        assert_eq!(detector.flagged(), [false]);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::aggregate::{self, HOURLY_TIME_FORMAT, TIME_FORMAT};
+use anyhow::{anyhow, Context, Result};
+use chrono::prelude::*;
+use parquet::basic::{Compression, LogicalType, Repetition, TimeUnit, Type as PhysicalType};
+use parquet::data_type::Int64Type;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::format::MilliSeconds;
+use parquet::schema::types::Type;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Rows of one month of a log, the time and the optional value of every other column
+type Month = Vec<(NaiveDateTime, Vec<Option<i64>>)>;
+
+/// Writes a node's raw and hourly log to `<out>/readings/node=<address>/month=<YYYY-MM>/` and
+/// `<out>/readings_hourly/...`, the hive layout pandas and duckdb read partitioned tables from.
+/// Returns the number of rows written.
+pub fn node(log_path: &Path, address: usize, out: &Path) -> Result<usize> {
+    let mut rows = 0;
+    for (table, path, time_format) in [
+        ("readings", log_path.to_owned(), TIME_FORMAT),
+        (
+            "readings_hourly",
+            aggregate::hourly_path(log_path),
+            HOURLY_TIME_FORMAT,
+        ),
+    ] {
+        if !path.exists() {
+            continue;
+        }
+        let (columns, months) = read_log(&path, time_format)?;
+        for (month, rows_of_month) in months {
+            let dir = out
+                .join(table)
+                .join(format!("node={}", address))
+                .join(format!("month={}", month));
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+            write_month(&dir.join("part-0.parquet"), &columns, &rows_of_month)?;
+            rows += rows_of_month.len();
+        }
+    }
+    Ok(rows)
+}
+
+/// Splits a log into months, rows with an unparseable time or a different column count are
+/// left out and empty values become nulls
+fn read_log(path: &Path, time_format: &str) -> Result<(Vec<String>, BTreeMap<String, Month>)> {
+    let log = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut lines = log.lines();
+    let header = lines.next().ok_or(anyhow!("{} is empty", path.display()))?;
+    let columns = header
+        .split(',')
+        .map(str::to_owned)
+        .collect::<Vec<String>>();
+
+    let mut months: BTreeMap<String, Month> = BTreeMap::new();
+    for line in lines {
+        let fields = line.split(',').collect::<Vec<&str>>();
+        if fields.len() != columns.len() {
+            continue;
+        }
+        let Ok(time) = NaiveDateTime::parse_from_str(fields[0], time_format) else {
+            continue;
+        };
+        let values = fields[1..]
+            .iter()
+            .map(|f| f.parse::<i64>().ok())
+            .collect::<Vec<Option<i64>>>();
+        months
+            .entry(time.format("%Y-%m").to_string())
+            .or_default()
+            .push((time, values));
+    }
+    Ok((columns, months))
+}
+
+fn write_month(path: &Path, columns: &[String], rows: &Month) -> Result<()> {
+    let mut fields = vec![Arc::new(
+        Type::primitive_type_builder(&columns[0], PhysicalType::INT64)
+            .with_repetition(Repetition::REQUIRED)
+            .with_logical_type(Some(LogicalType::Timestamp {
+                is_adjusted_to_u_t_c: false,
+                unit: TimeUnit::MILLIS(MilliSeconds {}),
+            }))
+            .build()?,
+    )];
+    for name in &columns[1..] {
+        fields.push(Arc::new(
+            Type::primitive_type_builder(name, PhysicalType::INT64)
+                .with_repetition(Repetition::OPTIONAL)
+                .build()?,
+        ));
+    }
+    let schema = Arc::new(
+        Type::group_type_builder("schema")
+            .with_fields(fields)
+            .build()?,
+    );
+    let properties = Arc::new(
+        WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build(),
+    );
+
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut writer = SerializedFileWriter::new(file, schema, properties)?;
+    let mut row_group = writer.next_row_group()?;
+    let mut index = 0;
+    while let Some(mut column) = row_group.next_column()? {
+        let writer = column.typed::<Int64Type>();
+        match index {
+            0 => {
+                let times = rows
+                    .iter()
+                    .map(|(t, _)| t.and_utc().timestamp_millis())
+                    .collect::<Vec<i64>>();
+                writer.write_batch(&times, None, None)?;
+            }
+            _ => {
+                let values = rows.iter().map(|(_, v)| v[index - 1]);
+                let levels = values
+                    .clone()
+                    .map(|v| v.is_some() as i16)
+                    .collect::<Vec<i16>>();
+                let present = values.flatten().collect::<Vec<i64>>();
+                writer.write_batch(&present, Some(&levels), None)?;
+            }
+        }
+        column.close()?;
+        index += 1;
+    }
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
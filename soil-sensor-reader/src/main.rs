@@ -1,54 +1,446 @@
-mod gateway;
+mod actuation;
+mod aggregate;
+mod anomaly;
+mod export;
+mod migrate;
+mod notify;
+mod pws;
+mod schedule;
+mod soil;
+mod stats;
 mod weather;
 
-use anyhow::{Context, Result};
+use actuation::{Cutoff, OpenSprinkler, OpenSprinklerConfig};
+use aggregate::TIME_FORMAT;
+use anomaly::{AnomalyConfig, AnomalyDetector, Transition};
+use anyhow::{anyhow, Context, Result};
 use chrono::prelude::*;
-use clap::Parser;
-use gateway::GatewayDriver;
-use gateway_host_schema::*;
+use chrono_tz::Tz;
+use clap::{Parser, Subcommand, ValueEnum};
+use lora_host_common::{
+    addressbook::{self, AddressBook, AddressBookAction, NodeRef},
+    gateway::GatewayDriver,
+    inventory::{self, Inventory},
+    lock::FileLock,
+    logging::{self, LogArgs},
+    metrics,
+    retry::RetryPolicy,
+    secret::Secret,
+};
+use notify::Notifier;
+use pws::{PwsConfig, PwsUploader};
+use schedule::DayTime;
 use serde::{Deserialize, Serialize};
-use serde_json;
+use serde_json::json;
+use soil::ZoneSettings;
+use std::collections::HashMap;
 use std::fs::OpenOptions;
-use std::{fs::File, io::Write, path::Path};
-use std::{thread::sleep, time::Duration};
-use weather::Weather;
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+use weather::{Fixtures, Forecast, Provider, Weather, WeatherConfig};
 
-/// LoRa module OTA updater
+/// Soil moisture sensor reader
 #[derive(Parser)]
 struct Args {
-    /// The device path to a serialport
-    port: String,
+    /// Site to run when config.json describes several sites
+    #[clap(long, global = true)]
+    site: Option<String>,
+
+    #[clap(flatten)]
+    log: LogArgs,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Poll every node once, print the readings as JSON and exit
+    ReadOnce {
+        #[clap(flatten)]
+        connection: ConnectionArgs,
+
+        #[clap(flatten)]
+        weather: WeatherArgs,
+
+        /// Also append the readings to the node logs the monitor writes
+        #[clap(long)]
+        log: bool,
+    },
+    /// Keep polling every node and logging the readings
+    Monitor {
+        #[clap(flatten)]
+        connection: ConnectionArgs,
+
+        #[clap(flatten)]
+        weather: WeatherArgs,
+
+        /// Format of the per-poll readings printed on stdout
+        #[clap(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+    },
+    /// Ping the gateways and show the last logged reading of every node
+    Status {
+        #[clap(flatten)]
+        connection: ConnectionArgs,
+    },
+    /// Dump the raw and hourly logs of every node into files partitioned by month
+    Export {
+        /// The node given to the other commands, the one logging to sensor_log.csv
+        destination_address: NodeRef,
+
+        #[clap(long, value_enum, default_value_t = ExportFormat::Parquet)]
+        format: ExportFormat,
+
+        /// Directory to write the files to
+        #[clap(short, long, default_value = "export")]
+        out: PathBuf,
+    },
+    /// Print moisture per zone, watering and failed polls of every node over a date range
+    Stats {
+        /// The node given to the other commands, the one logging to sensor_log.csv
+        destination_address: NodeRef,
+
+        /// First day to include, e.g. 2024-07-01
+        #[clap(long)]
+        from: Option<NaiveDate>,
+
+        /// Last day to include
+        #[clap(long)]
+        to: Option<NaiveDate>,
+    },
+    /// Show the node names, or name a node so commands can be given the name instead
+    Addressbook {
+        #[clap(subcommand)]
+        action: Option<AddressBookAction>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    Parquet,
+}
+
+#[derive(clap::Args)]
+struct ConnectionArgs {
+    /// The serialport of the gateway, e.g. /dev/ttyACM0 or COM3, `auto` picks the only USB one
+    /// [default: gateway.port in the config]
+    port: Option<String>,
+
+    /// The node address or its name in the address book [default: gateway.node in the config]
+    node: Option<NodeRef>,
 
-    /// The node address
+    /// The baudrate to open the port with [default: gateway.baudrate in the config, then
+    /// 115200]
+    #[clap(short, long)]
+    baudrate: Option<u32>,
+
+    /// `node` looked up in the address book
+    #[clap(skip)]
     destination_address: usize,
 
-    /// OpenWeather version 2.5 token
-    weather_token: String,
+    /// The gateway of `node`, from the command line or the config
+    #[clap(skip)]
+    gateway: GatewayConfig,
+}
 
-    /// The baudrate to open the port with
-    #[clap(short, long, default_value = "115200")]
-    baudrate: u32,
+#[derive(clap::Args)]
+struct WeatherArgs {
+    /// OpenWeather One Call token, `env:<NAME>` or `file:<path>` keep it out of the shell
+    /// history [default: weather.token in the config, then $OPENWEATHER_TOKEN]
+    weather_token: Option<Secret>,
+
+    /// Append every weather provider response to `<provider>.jsonl` in this directory
+    #[clap(long, value_name = "DIR")]
+    record_weather: Option<PathBuf>,
+
+    /// Serve the responses recorded with --record-weather instead of asking the providers, no
+    /// token is needed then
+    #[clap(long, value_name = "DIR", conflicts_with = "record_weather")]
+    replay_weather: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    /// Human readable line per poll
+    Text,
+    /// Single JSON object per poll
+    Json,
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct Config {
+    timezone: Tz,
     latitude: f64,
     longitude: f64,
-    sensor_cal_low: [u16; 4],
-    sensor_cal_high: [u16; 4],
+    /// Raw reading of each zone's probe in dry soil, one entry per zone sets the zone count
+    sensor_cal_low: Vec<u16>,
+    /// Raw reading of each zone's probe in saturated soil
+    sensor_cal_high: Vec<u16>,
+    /// Zone of each probe channel, `zone1`, `zone2`, ... when left out, channels sharing a label
+    /// are probes of one zone, e.g. a deep and a shallow one
+    #[serde(default)]
+    zone_labels: Vec<String>,
+    /// Soil profile and overrides per zone label, zones left out use the site-wide settings
+    #[serde(default)]
+    zones: HashMap<String, ZoneSettings>,
     moisture_threshold: f64,
+    /// Thresholds overriding `moisture_threshold` from a time of day until the next entry
+    #[serde(default)]
+    moisture_schedule: Vec<ThresholdEntry>,
     precipitation_threshold: f64,
-    day_start_hour: u32,
-    day_end_hour: u32,
+    /// Forecast providers and how their forecasts are combined, OpenWeather alone by default
+    #[serde(default)]
+    weather: WeatherConfig,
+    /// Scales station run times with the forecast heat and rain, runs keep their length when
+    /// left out
+    #[serde(default)]
+    watering_depth: Option<DepthConfig>,
+    /// Wind speed in m/s above which watering is skipped to avoid spray drift
+    #[serde(default)]
+    wind_threshold: Option<f64>,
+    day_start: DayTime,
+    day_end: DayTime,
+    /// Consecutive failed polls tolerated before alerting and disabling watering
+    #[serde(default = "default_failure_budget")]
+    failure_budget: u32,
+    /// Extra attempts at a sensor request before the poll counts as failed
+    #[serde(default = "default_poll_retries")]
+    poll_retries: u32,
+    /// Delay before the first retry in milliseconds, doubled for every further one and jittered
+    #[serde(default = "default_retry_backoff_ms")]
+    retry_backoff_ms: u64,
+    /// Webhook receiving alerts as `{"message": ...}` JSON posts
+    #[serde(default)]
+    notify_url: Option<String>,
+    /// Gateway and node the command line may leave out, so that each site polls its own
+    #[serde(default)]
+    gateway: Option<PrimaryGateway>,
+    /// Gateways polled in addition to the one given on the command line
+    #[serde(default)]
+    gateways: Vec<GatewayConfig>,
+    /// Consecutive failed polls on a gateway after which it is pinged and, when it does not
+    /// answer, reopened, 0 never reopens it
+    #[serde(default = "default_gateway_restart_after")]
+    gateway_restart_after: u32,
+    /// Per-poll moisture change bounds, zones exceeding them are left out of the decision
+    #[serde(default)]
+    anomaly: Option<AnomalyConfig>,
+    /// Raw log rows older than this are rolled up into hourly averages once a day
+    #[serde(default)]
+    retention_days: Option<u32>,
+    /// OpenSprinkler controller driving the valves of the mapped zones
+    #[serde(default)]
+    opensprinkler: Option<OpenSprinklerConfig>,
+    /// Personal weather station receiving the moisture of some zones
+    #[serde(default)]
+    pws: Option<PwsConfig>,
+    /// The module updater's inventory, recording the firmware each node was last updated with
+    #[serde(default = "default_inventory")]
+    inventory: PathBuf,
+    /// Node names shared with the module updater
+    #[serde(default = "default_addressbook")]
+    addressbook: PathBuf,
+    /// Directory holding this site's logs and state
+    #[serde(skip)]
+    data_dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ThresholdEntry {
+    from: DayTime,
+    threshold: f64,
+}
+
+/// Lengthens station runs ahead of a hot day and shortens them when rain is expected
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DepthConfig {
+    /// Highest temperature of the next 24 hours in °C above which runs get longer
+    #[serde(default = "default_base_temperature")]
+    base_temperature: f64,
+    /// Share of a run added for every degree above `base_temperature`
+    #[serde(default = "default_per_degree")]
+    per_degree: f64,
+    /// Rain expected over the next 24 hours in mm that makes watering pointless, less rain
+    /// shortens runs in proportion
+    #[serde(default = "default_rain_mm")]
+    rain_mm: f64,
+    /// Longest runs may get relative to their configured length
+    #[serde(default = "default_max_factor")]
+    max_factor: f64,
+}
+
+impl DepthConfig {
+    /// Factor the configured run times are scaled by
+    fn factor(&self, forecast: &Forecast) -> f64 {
+        let heat =
+            1.0 + (forecast.max_temperature - self.base_temperature).max(0.0) * self.per_degree;
+        let rain = (1.0 - forecast.precipitation / self.rain_mm).max(0.0);
+        (heat * rain).min(self.max_factor)
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct GatewayConfig {
+    port: String,
+    #[serde(default = "default_baudrate")]
+    baudrate: u32,
+    nodes: Vec<usize>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PrimaryGateway {
+    port: String,
+    #[serde(default = "default_baudrate")]
+    baudrate: u32,
+    /// The node logging to sensor_log.csv
+    node: usize,
+}
+
+fn default_baudrate() -> u32 {
+    115200
+}
+
+fn default_failure_budget() -> u32 {
+    20
+}
+
+fn default_poll_retries() -> u32 {
+    2
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    200
+}
+
+fn default_base_temperature() -> f64 {
+    25.0
+}
+
+fn default_per_degree() -> f64 {
+    0.05
+}
+
+fn default_rain_mm() -> f64 {
+    10.0
+}
+
+fn default_max_factor() -> f64 {
+    2.0
+}
+
+fn default_gateway_restart_after() -> u32 {
+    10
+}
+
+fn default_inventory() -> PathBuf {
+    PathBuf::from(inventory::DEFAULT_PATH)
+}
+
+fn default_addressbook() -> PathBuf {
+    PathBuf::from(addressbook::DEFAULT_PATH)
+}
+
+impl Config {
+    /// The moisture threshold in effect at `now`, the schedule wraps around midnight
+    fn moisture_threshold_at(&self, now: &DateTime<Tz>) -> f64 {
+        let date = now.date_naive();
+        let starts = self
+            .moisture_schedule
+            .iter()
+            .filter_map(|e| {
+                e.from
+                    .resolve(date, &self.timezone, self.latitude, self.longitude)
+                    .map(|from| (from, e.threshold))
+            })
+            .collect::<Vec<_>>();
+        starts
+            .iter()
+            .filter(|(from, _)| from <= now)
+            .max_by_key(|(from, _)| *from)
+            .or(starts.iter().max_by_key(|(from, _)| *from))
+            .map(|(_, threshold)| *threshold)
+            .unwrap_or(self.moisture_threshold)
+    }
+
+    /// Whether `now` falls into today's watering window
+    fn in_day_window(&self, now: &DateTime<Tz>) -> bool {
+        let date = now.date_naive();
+        let resolve = |t: &DayTime| t.resolve(date, &self.timezone, self.latitude, self.longitude);
+        match (resolve(&self.day_start), resolve(&self.day_end)) {
+            (Some(start), Some(end)) => *now >= start && *now < end,
+            _ => false,
+        }
+    }
+}
+
+/// Why the watering decision came out the way it did
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Reason {
+    Watering,
+    NoPlausibleZones,
+    MoistEnough,
+    OutsideWindow,
+    RainExpected,
+    Windy,
+    BudgetExhausted,
+    RunLimitReached,
+    NoForecast,
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Reason::Watering => "watering",
+            Reason::NoPlausibleZones => "no plausible zones",
+            Reason::MoistEnough => "moist enough",
+            Reason::OutsideWindow => "outside the watering window",
+            Reason::RainExpected => "rain expected",
+            Reason::Windy => "too windy",
+            Reason::BudgetExhausted => "weekly water budget used up",
+            Reason::RunLimitReached => "daily run limit reached",
+            Reason::NoForecast => "no forecast",
+        })
+    }
 }
 
 struct WateringResult {
     watering: bool,
+    reason: Reason,
     moisture: f64,
+    threshold: f64,
+    zones: Vec<f64>,
+    anomalies: Vec<bool>,
+    /// Moisture of each zone combined from its probes
+    combined: Vec<ZoneReading>,
+    /// Factor the station run times are scaled by
+    duration_factor: f64,
 }
 
-fn figure_out_watering(config: &Config, moisture: [u16; 4], pop: f64) -> WateringResult {
-    let moisture = moisture
+/// A zone's moisture combined from all of its probes
+struct ZoneReading {
+    label: String,
+    moisture: f64,
+    /// Whether any of the zone's probes gave a plausible reading
+    plausible: bool,
+}
+
+/// Maps raw sensor values onto 0..1 using the per-zone calibration
+fn normalize(config: &Config, moisture: &[u16]) -> Vec<f64> {
+    moisture
         .iter()
         .zip(
             config
@@ -57,81 +449,1347 @@ fn figure_out_watering(config: &Config, moisture: [u16; 4], pop: f64) -> Waterin
                 .zip(config.sensor_cal_high.iter()),
         )
         .map(|(m, (low, high))| ((*m).clamp(*low, *high) - *low) as f64 / (*high - *low) as f64)
-        .collect::<Vec<f64>>();
+        .collect::<Vec<f64>>()
+}
+
+/// Combines the channels sharing a label and the zone's remote probes, the latest plausible
+/// reading of a remote channel is looked up by `remote`. Implausible probes only count when none
+/// of the zone's probes is plausible.
+fn combine_probes(
+    config: &Config,
+    channels: &[f64],
+    anomalies: &[bool],
+    remote: impl Fn(usize, usize) -> Option<f64>,
+) -> Vec<ZoneReading> {
+    let mut labels = Vec::<String>::new();
+    for label in &config.zone_labels {
+        if !labels.contains(label) {
+            labels.push(label.clone());
+        }
+    }
+    labels
+        .into_iter()
+        .map(|label| {
+            let settings = config.zones.get(&label).cloned().unwrap_or_default();
+            let own = (0..channels.len()).filter(|i| config.zone_labels[*i] == label);
+            let mut plausible = own
+                .clone()
+                .filter(|i| !anomalies[*i])
+                .map(|i| channels[i])
+                .collect::<Vec<f64>>();
+            plausible.extend(
+                settings
+                    .remote_probes
+                    .iter()
+                    .filter_map(|p| remote(p.node, p.channel)),
+            );
+            let moisture = settings.aggregate.apply(&plausible);
+            ZoneReading {
+                plausible: moisture.is_some(),
+                moisture: moisture
+                    .or(settings
+                        .aggregate
+                        .apply(&own.map(|i| channels[i]).collect::<Vec<f64>>()))
+                    .unwrap_or(0.0),
+                label,
+            }
+        })
+        .collect()
+}
 
-    let moisture = moisture.iter().fold(0.0, |acc, m| acc + m) / moisture.len() as f64;
-    let hours = Local::now().hour();
+/// Decides on watering from the plausible zones, `was_watering` raises the threshold by the
+/// zones' hysteresis so that watering keeps going until the soil is comfortably moist again.
+/// How long stations run follows the forecast heat and rain when `watering_depth` is set.
+/// Without a forecast the soil may be dry but nothing is watered.
+fn figure_out_watering(
+    config: &Config,
+    now: &DateTime<Tz>,
+    zones: Vec<f64>,
+    anomalies: &[bool],
+    combined: Vec<ZoneReading>,
+    was_watering: bool,
+    forecast: Option<&Forecast>,
+) -> WateringResult {
+    let valid = (0..combined.len())
+        .filter(|i| combined[*i].plausible)
+        .collect::<Vec<usize>>();
+    // without a single plausible zone there is nothing to base the decision on
+    let (considered, plausible) = match valid.is_empty() {
+        true => ((0..combined.len()).collect::<Vec<usize>>(), false),
+        false => (valid, true),
+    };
+    let average = |f: &dyn Fn(usize) -> f64| {
+        considered.iter().fold(0.0, |acc, i| acc + f(*i)) / considered.len() as f64
+    };
+
+    let default_threshold = config.moisture_threshold_at(now);
+    let settings = |i: usize| config.zones.get(&combined[i].label);
+    let moisture = average(&|i| combined[i].moisture);
+    let mut threshold = average(&|i| {
+        settings(i)
+            .and_then(|z| z.moisture_threshold())
+            .unwrap_or(default_threshold)
+    });
+    if was_watering {
+        threshold += average(&|i| settings(i).and_then(|z| z.hysteresis()).unwrap_or(0.0));
+    }
+    let reason = if !plausible {
+        Reason::NoPlausibleZones
+    } else if moisture >= threshold / 100.0 {
+        Reason::MoistEnough
+    } else if !config.in_day_window(now) {
+        Reason::OutsideWindow
+    } else {
+        let rainy =
+            |f: &Forecast| f.precipitation_probability >= config.precipitation_threshold / 100.0;
+        let windy = |f: &Forecast| {
+            config
+                .wind_threshold
+                .is_some_and(|limit| f.wind_speed > limit)
+        };
+        match forecast {
+            None => Reason::NoForecast,
+            Some(f) if rainy(f) => Reason::RainExpected,
+            Some(f) if windy(f) => Reason::Windy,
+            Some(_) => Reason::Watering,
+        }
+    };
 
     WateringResult {
-        watering: moisture < (config.moisture_threshold / 100.0)
-            && pop < (config.precipitation_threshold / 100.0)
-            && (hours >= config.day_start_hour && hours < config.day_end_hour),
+        watering: reason == Reason::Watering,
+        reason,
         moisture,
+        threshold,
+        zones,
+        anomalies: anomalies.to_vec(),
+        combined,
+        duration_factor: config
+            .watering_depth
+            .as_ref()
+            .zip(forecast)
+            .map_or(1.0, |(d, f)| d.factor(f)),
     }
 }
 
+fn print_reading(
+    config: &Config,
+    format: OutputFormat,
+    node: &Node,
+    now: &DateTime<Tz>,
+    raw: &[u16],
+    forecast: Option<&Forecast>,
+    watering: &WateringResult,
+) {
+    match format {
+        OutputFormat::Text => println!(
+            "Node {}: {} ({}{})",
+            node.address,
+            config
+                .zone_labels
+                .iter()
+                .zip(raw.iter())
+                .map(|(label, m)| format!("{}: {}", label, m))
+                .collect::<Vec<String>>()
+                .join(", "),
+            watering.reason,
+            match forecast.map(|f| f.fallback) {
+                Some(true) => ", seasonal weather",
+                _ => "",
+            }
+        ),
+        OutputFormat::Json => println!(
+            "{}",
+            json!({
+                "timestamp": now.to_rfc3339(),
+                "node": node.address,
+                "firmware": node.firmware,
+                "zones": config
+                    .zone_labels
+                    .iter()
+                    .zip(raw.iter().zip(watering.zones.iter()))
+                    .zip(watering.anomalies.iter())
+                    .map(|((label, (raw, normalized)), anomaly)| json!({
+                        "label": label,
+                        "raw": raw,
+                        "normalized": normalized,
+                        "anomaly": anomaly,
+                    }))
+                    .collect::<Vec<_>>(),
+                "zone_moisture": watering
+                    .combined
+                    .iter()
+                    .map(|z| (z.label.clone(), json!(z.moisture)))
+                    .collect::<serde_json::Map<_, _>>(),
+                "moisture": watering.moisture,
+                "threshold": watering.threshold / 100.0,
+                "watering": watering.watering,
+                "reason": watering.reason,
+                "duration_factor": watering.duration_factor,
+                "weather": forecast.map(|f| json!({
+                    "precipitation_probability": f.precipitation_probability,
+                    "wind_speed": f.wind_speed,
+                    "max_temperature": f.max_temperature,
+                    "precipitation": f.precipitation,
+                    "fallback": f.fallback,
+                })),
+            })
+        ),
+    }
+}
+
+const STATE_FILE: &str = "reader_state.json";
+/// Held by the running monitor, the state file outlives it
+const LOCK_FILE: &str = "reader.lock";
+/// Probe channels a sensor node reports, the most zones a node can have
+const SENSOR_CHANNELS: usize = 4;
+/// Readings of remote probes older than this are left out of a zone
+const PROBE_MAX_AGE: Duration = Duration::from_secs(600);
+const METRICS_REPORT_INTERVAL: Duration = Duration::from_secs(3600);
+/// Retries of the weather, controller and webhook requests before giving up until the next
+/// round
+const HTTP_RETRY: RetryPolicy = RetryPolicy {
+    retries: 2,
+    backoff: Duration::from_secs(1),
+};
+
+/// Failures of an HTTP request worth repeating it for: timeouts, unreachable hosts, rate
+/// limiting and server errors, a retry predicate for [`RetryPolicy::run_if`]
+fn transient_http_error(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<reqwest::Error>().is_some_and(|e| {
+        e.is_timeout()
+            || e.is_connect()
+            || e.status()
+                .is_some_and(|s| s.is_server_error() || s.as_u16() == 429)
+    })
+}
+
+/// The request never reached the server, the only safe failure to repeat requests that
+/// act for
+fn connect_error(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<reqwest::Error>()
+        .is_some_and(reqwest::Error::is_connect)
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
-    let config: Config = serde_json::from_reader(
+    logging::init(&args.log)?;
+    let mut config = load_config(args.site.as_deref())?;
+    let mut book = AddressBook::load(&config.addressbook)?;
+
+    match args.command {
+        Command::ReadOnce {
+            mut connection,
+            weather,
+            log,
+        } => {
+            connection.resolve(&config, &book)?;
+            set_weather(&mut config, weather)?;
+            let mut gateways = open_gateways(&connection, &config, log)?;
+            let mut reader = Reader::new(config, OutputFormat::Json);
+            for (link, nodes) in gateways.iter_mut() {
+                for node in nodes.iter_mut() {
+                    reader.poll(link.driver.as_mut(), node);
+                }
+            }
+            reader.log_report();
+            match gateways
+                .iter()
+                .flat_map(|(_, n)| n)
+                .find(|n| n.failures > 0)
+            {
+                Some(node) => Err(anyhow!("Failed to read node {}", node.address)),
+                None => Ok(()),
+            }
+        }
+        Command::Monitor {
+            mut connection,
+            weather,
+            output,
+        } => {
+            connection.resolve(&config, &book)?;
+            set_weather(&mut config, weather)?;
+            let _lock = FileLock::try_acquire(&config.data_dir.join(LOCK_FILE))?.ok_or(anyhow!(
+                "A monitor is already running on {}",
+                config.data_dir.display()
+            ))?;
+            let mut gateways = open_gateways(&connection, &config, true)?;
+            let mut reader = Reader::new(config, output);
+            std::fs::write(
+                reader.config.data_dir.join(STATE_FILE),
+                json!({
+                    "pid": std::process::id(),
+                    "started": Utc::now().to_rfc3339(),
+                })
+                .to_string(),
+            )
+            .context("Failed to write the state file")?;
+            #[cfg(unix)]
+            stop_on_signal();
+
+            let mut compacted_on = None;
+            let mut reported_at = Instant::now();
+            while !STOP.load(Ordering::Relaxed) {
+                if let Some(days) = reader.config.retention_days {
+                    let now = Utc::now()
+                        .with_timezone(&reader.config.timezone)
+                        .naive_local();
+                    if compacted_on != Some(now.date()) {
+                        let cutoff = now - chrono::Duration::days(days as i64);
+                        for node in gateways.iter_mut().flat_map(|(_, nodes)| nodes.iter_mut()) {
+                            if let Err(e) = node.compact(cutoff) {
+                                warn!("Node {}: failed to compact the log: {:#}", node.address, e);
+                            }
+                        }
+                        compacted_on = Some(now.date());
+                    }
+                }
+
+                for (link, nodes) in gateways.iter_mut() {
+                    for node in nodes.iter_mut() {
+                        reader.poll(link.driver.as_mut(), node);
+                        reader.watch_link(link, node.failures == 0);
+                    }
+                }
+
+                reader.upload_to_pws();
+
+                if reported_at.elapsed() >= METRICS_REPORT_INTERVAL {
+                    reader.log_report();
+                    reported_at = Instant::now();
+                }
+
+                for _ in 0..15 {
+                    if STOP.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    sleep(Duration::from_secs(1));
+                }
+            }
+            info!("Stopping");
+            match reader.sprinkler.as_mut() {
+                Some(sprinkler) => sprinkler
+                    .close_all()
+                    .context("Failed to close the OpenSprinkler stations"),
+                None => Ok(()),
+            }
+        }
+        Command::Status { mut connection } => {
+            connection.resolve(&config, &book)?;
+            status(&connection, &config)
+        }
+        Command::Export {
+            destination_address,
+            format: ExportFormat::Parquet,
+            out,
+        } => {
+            let destination_address = book.address(&destination_address)?;
+            let addresses = std::iter::once(destination_address)
+                .chain(config.gateways.iter().flat_map(|g| g.nodes.iter().cloned()));
+            for address in addresses {
+                let path = log_path(destination_address, &config, address);
+                let rows = export::node(&path, address, &out)
+                    .with_context(|| format!("Failed to export node {}", address))?;
+                info!("Node {}: exported {} rows", address, rows);
+            }
+            Ok(())
+        }
+        Command::Stats {
+            destination_address,
+            from,
+            to,
+        } => {
+            let destination_address = book.address(&destination_address)?;
+            let percent = |channel: usize, raw: f64| {
+                let low = *config.sensor_cal_low.get(channel)? as f64;
+                let high = *config.sensor_cal_high.get(channel)? as f64;
+                Some((raw.clamp(low, high) - low) / (high - low) * 100.0)
+            };
+            let addresses = std::iter::once(destination_address)
+                .chain(config.gateways.iter().flat_map(|g| g.nodes.iter().cloned()));
+            for address in addresses {
+                let path = log_path(destination_address, &config, address);
+                let stats = stats::node(&path, from, to, percent)
+                    .with_context(|| format!("Failed to read the log of node {}", address))?;
+                println!("Node {}:", address);
+                for zone in &stats.zones {
+                    match zone.average() {
+                        Some(average) => println!(
+                            "  {}: min {:.0}%, avg {:.0}%, max {:.0}%",
+                            zone.label, zone.min, average, zone.max
+                        ),
+                        None => println!("  {}: no readings", zone.label),
+                    }
+                }
+                println!(
+                    "  watering decided in {} of {} polls, {} polls failed",
+                    stats.watering, stats.polls, stats.degraded
+                );
+            }
+            Ok(())
+        }
+        Command::Addressbook { action } => book.manage(action),
+    }
+}
+
+/// Loads config.json, either a single site or a `{"sites": {"name": {...}}}` map of sites
+/// whose logs and state are kept in a directory named after the site. A file written for an
+/// older reader is migrated, the original is kept as `config.json.v<version>.bak`.
+fn load_config(site: Option<&str>) -> Result<Config> {
+    let path = Path::new("config.json");
+    let mut file: serde_json::Value = serde_json::from_reader(
         OpenOptions::new()
             .read(true)
-            .open(Path::new("config.json"))
+            .open(path)
             .context("Failed to open config file")?,
     )
     .context("Failed to parse config file")?;
-    let mut weather = Weather::new(config.latitude, config.longitude, args.weather_token);
-
-    let mut gateway =
-        GatewayDriver::new(&args.port, args.baudrate).context("Failed to open port")?;
-    gateway.ping().context("Failed to connect to Gateway")?;
-
-    let output_path = Path::new("sensor_log.csv");
-    let mut output_path = match output_path.exists() {
-        true => OpenOptions::new()
-            .append(true)
-            .open(output_path)
-            .context("Failed to open output file")?,
-        false => {
-            let mut f = File::create(output_path).context("Failed to create output file")?;
-            f.write_all("time,zone1,zone2,zone3,zone4,moisture,pop,water\n".as_bytes())?;
-            f
+
+    let version = migrate::migrate(&mut file)?;
+    if version < migrate::CONFIG_VERSION {
+        let backup = path.with_extension(format!("json.v{}.bak", version));
+        std::fs::copy(path, &backup).context("Failed to back up the config file")?;
+        std::fs::write(path, serde_json::to_string_pretty(&file)? + "\n")
+            .context("Failed to write the migrated config file")?;
+        info!(
+            "Migrated config.json from version {} to {}, the original is in {}",
+            version,
+            migrate::CONFIG_VERSION,
+            backup.display()
+        );
+    }
+    let root = file.as_object_mut().unwrap();
+    root.remove("version");
+    if let Some(key) = root
+        .keys()
+        .find(|k| root.contains_key("sites") && *k != "sites")
+    {
+        return Err(anyhow!("Unknown key \"{}\" next to \"sites\"", key));
+    }
+
+    let (site_config, data_dir) = match (file.get_mut("sites"), site) {
+        (None, None) => (file, PathBuf::from(".")),
+        (None, Some(_)) => return Err(anyhow!("The config file does not define any sites")),
+        (Some(sites), site) => {
+            let sites = sites
+                .as_object_mut()
+                .ok_or(anyhow!("\"sites\" must be an object"))?;
+            // the name becomes a directory, it must not reach outside the working one
+            if let Some(name) = sites.keys().find(|n| {
+                n.is_empty()
+                    || !n
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            }) {
+                return Err(anyhow!(
+                    "Invalid site name \"{}\", use letters, digits, '_' and '-'",
+                    name
+                ));
+            }
+            let name = match site {
+                Some(name) => name.to_owned(),
+                None if sites.len() == 1 => sites.keys().next().unwrap().clone(),
+                None => {
+                    return Err(anyhow!(
+                        "Select a site with --site, available: {}",
+                        sites.keys().cloned().collect::<Vec<String>>().join(", ")
+                    ))
+                }
+            };
+            let config = sites
+                .remove(&name)
+                .ok_or(anyhow!("Site \"{}\" is not in the config file", name))?;
+            std::fs::create_dir_all(&name)
+                .with_context(|| format!("Failed to create the site directory {}", name))?;
+            (config, PathBuf::from(name))
         }
     };
+    let mut config: Config =
+        serde_json::from_value(site_config).context("Failed to parse config file")?;
+    config.data_dir = data_dir;
+    let zones = config.sensor_cal_low.len();
+    if !(1..=SENSOR_CHANNELS).contains(&zones) {
+        return Err(anyhow!(
+            "sensor_cal_low must have an entry for each of 1 to {} zones, it has {}",
+            SENSOR_CHANNELS,
+            zones
+        ));
+    }
+    if config.zone_labels.is_empty() {
+        config.zone_labels = (1..=zones).map(|i| format!("zone{}", i)).collect();
+    }
+    if config.sensor_cal_high.len() != zones || config.zone_labels.len() != zones {
+        return Err(anyhow!(
+            "sensor_cal_low, sensor_cal_high and zone_labels must have an entry per zone, \
+             they have {}, {} and {}",
+            zones,
+            config.sensor_cal_high.len(),
+            config.zone_labels.len()
+        ));
+    }
+    if let Some(i) = (0..zones).find(|&i| config.sensor_cal_low[i] >= config.sensor_cal_high[i]) {
+        return Err(anyhow!(
+            "sensor_cal_low must be below sensor_cal_high, it is not for {}",
+            config.zone_labels[i]
+        ));
+    }
+    if let Some(label) = config
+        .zone_labels
+        .iter()
+        .find(|l| l.is_empty() || l.contains(','))
+    {
+        return Err(anyhow!("Invalid zone label \"{}\"", label));
+    }
+    if let Some(zone) = config
+        .zones
+        .keys()
+        .find(|z| !config.zone_labels.contains(z))
+    {
+        return Err(anyhow!("Settings given for unknown zone \"{}\"", zone));
+    }
+    if let Some((zone, probe)) = config
+        .zones
+        .iter()
+        .flat_map(|(z, s)| s.remote_probes.iter().map(move |p| (z, p)))
+        .find(|(_, p)| p.channel >= zones)
+    {
+        return Err(anyhow!(
+            "Remote probe of zone \"{}\" on node {} uses channel {}, there are {} zones",
+            zone,
+            probe.node,
+            probe.channel,
+            zones
+        ));
+    }
+    if let Some(mapping) = config
+        .opensprinkler
+        .iter()
+        .flat_map(|o| o.stations.iter())
+        .find(|m| !config.zone_labels.contains(&m.zone))
+    {
+        return Err(anyhow!(
+            "OpenSprinkler station {} is mapped to unknown zone \"{}\"",
+            mapping.station,
+            mapping.zone
+        ));
+    }
+    if let Some(depth) = config.watering_depth.as_ref().filter(|d| d.rain_mm <= 0.0) {
+        return Err(anyhow!(
+            "watering_depth.rain_mm must be positive, it is {}",
+            depth.rain_mm
+        ));
+    }
+    if config.weather.providers.is_empty() {
+        return Err(anyhow!("At least one weather provider is needed"));
+    }
+    if let Some(p) = config
+        .weather
+        .providers
+        .iter()
+        .find(|p| p.weight.is_nan() || p.weight <= 0.0)
+    {
+        return Err(anyhow!(
+            "The weight of {} must be positive, it is {}",
+            p.provider,
+            p.weight
+        ));
+    }
+    if let Some(pws) = &config.pws {
+        if pws.zones.len() > pws::MOISTURE_FIELDS {
+            return Err(anyhow!(
+                "The weather station takes at most {} zones, {} are configured",
+                pws::MOISTURE_FIELDS,
+                pws.zones.len()
+            ));
+        }
+        if let Some(zone) = pws
+            .zones
+            .iter()
+            .find(|z| !config.zone_labels.contains(&z.zone))
+        {
+            return Err(anyhow!(
+                "Weather station upload of unknown zone \"{}\"",
+                zone.zone
+            ));
+        }
+    }
+    Ok(config)
+}
+
+/// Takes the OpenWeather token from the command line, the config or the environment, in this
+/// order, it is only required when OpenWeather is one of the providers and not replayed
+fn set_weather(config: &mut Config, args: WeatherArgs) -> Result<()> {
+    config.weather.fixtures = match (args.record_weather, args.replay_weather) {
+        (Some(dir), _) => Some(Fixtures::Record(dir)),
+        (None, Some(dir)) => Some(Fixtures::Replay(dir)),
+        (None, None) => None,
+    };
+    let token = Secret::or_env(
+        args.weather_token.or(config.weather.token.take()),
+        "OPENWEATHER_TOKEN",
+    )?;
+    let replaying = matches!(config.weather.fixtures, Some(Fixtures::Replay(_)));
+    let needed = config
+        .weather
+        .providers
+        .iter()
+        .any(|p| p.provider == Provider::OpenWeather);
+    if needed && !replaying && token.is_none() {
+        return Err(anyhow!(
+            "No OpenWeather token, pass it, set weather.token in the config or $OPENWEATHER_TOKEN"
+        ));
+    }
+    config.weather.token = token;
+    Ok(())
+}
+
+impl ConnectionArgs {
+    /// Takes what the command line leaves out from the site's gateway and looks the node up
+    /// in the address book
+    fn resolve(&mut self, config: &Config, book: &AddressBook) -> Result<()> {
+        let site = config.gateway.as_ref();
+        let port = match (&self.port, site) {
+            (Some(port), _) => port.clone(),
+            (None, Some(g)) => g.port.clone(),
+            (None, None) => {
+                return Err(anyhow!(
+                    "No gateway port, pass it or set gateway.port in the config"
+                ))
+            }
+        };
+        self.destination_address = match (&self.node, site) {
+            (Some(node), _) => book.address(node)?,
+            (None, Some(g)) => g.node,
+            (None, None) => {
+                return Err(anyhow!(
+                    "No node, pass it or set gateway.node in the config"
+                ))
+            }
+        };
+        self.gateway = GatewayConfig {
+            port,
+            baudrate: self
+                .baudrate
+                .or(site.map(|g| g.baudrate))
+                .unwrap_or(default_baudrate()),
+            nodes: vec![self.destination_address],
+        };
+        Ok(())
+    }
+}
+
+/// All gateways to poll, the one of the node given on the command line first
+fn gateway_configs(connection: &ConnectionArgs, config: &Config) -> Result<Vec<GatewayConfig>> {
+    let mut gateways = vec![connection.gateway.clone()];
+    gateways.extend(config.gateways.iter().cloned());
+
+    let addresses = gateways
+        .iter()
+        .flat_map(|g| g.nodes.iter())
+        .collect::<Vec<_>>();
+    for (i, a) in addresses.iter().enumerate() {
+        if addresses[..i].contains(a) {
+            return Err(anyhow!("Node address {} is configured more than once", a));
+        }
+    }
+    Ok(gateways)
+}
+
+fn log_path(destination_address: usize, config: &Config, address: usize) -> PathBuf {
+    if address == destination_address {
+        config.data_dir.join("sensor_log.csv")
+    } else {
+        config.data_dir.join(format!("sensor_log_{}.csv", address))
+    }
+}
+
+/// Opens and pings the gateways, with `log` the node logs are opened for appending
+fn open_gateways(
+    connection: &ConnectionArgs,
+    config: &Config,
+    log: bool,
+) -> Result<Vec<(Link, Vec<Node>)>> {
+    let mut gateways = Vec::new();
+    for g in gateway_configs(connection, config)? {
+        let mut gateway = GatewayDriver::new(&g.port, g.baudrate)
+            .with_context(|| format!("Failed to open port {}", g.port))?;
+        gateway
+            .ping()
+            .with_context(|| format!("Failed to connect to Gateway on {}", g.port))?;
+        let nodes = g
+            .nodes
+            .iter()
+            .map(|a| {
+                let path = log_path(connection.destination_address, config, *a);
+                Node::new(config, *a, &path, log)
+            })
+            .collect::<Result<Vec<Node>>>()?;
+        let link = Link {
+            port: g.port,
+            baudrate: g.baudrate,
+            driver: Some(gateway),
+            failures: 0,
+        };
+        gateways.push((link, nodes));
+    }
+    Ok(gateways)
+}
+
+fn status(connection: &ConnectionArgs, config: &Config) -> Result<()> {
+    match std::fs::read_to_string(config.data_dir.join(STATE_FILE)) {
+        Ok(state) => {
+            let state: serde_json::Value =
+                serde_json::from_str(&state).context("Failed to parse the state file")?;
+            let started = state["started"]
+                .as_str()
+                .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+                .ok_or(anyhow!("Invalid start time in the state file"))?;
+            if FileLock::is_held(&config.data_dir.join(LOCK_FILE))? {
+                let uptime = Utc::now().signed_duration_since(started);
+                println!(
+                    "Monitor running since {}, up {}d {}h {}m",
+                    started.with_timezone(&config.timezone).format(TIME_FORMAT),
+                    uptime.num_days(),
+                    uptime.num_hours() % 24,
+                    uptime.num_minutes() % 60
+                );
+            } else {
+                println!(
+                    "Monitor not running, last started {}",
+                    started.with_timezone(&config.timezone).format(TIME_FORMAT)
+                );
+            }
+        }
+        Err(_) => println!("Monitor has never been started here"),
+    }
+
+    for g in gateway_configs(connection, config)? {
+        match GatewayDriver::new(&g.port, g.baudrate).and_then(|mut gateway| gateway.ping()) {
+            Ok(latency) => println!("Gateway {}: ping {} ms", g.port, latency.as_millis()),
+            Err(e) => println!("Gateway {}: unreachable: {:#}", g.port, e),
+        }
+        for address in g.nodes {
+            let path = log_path(connection.destination_address, config, address);
+            let log = std::fs::read_to_string(&path).unwrap_or_default();
+            let mut lines = log.lines().filter(|l| !l.is_empty());
+            match (lines.next(), lines.next_back()) {
+                (Some(header), Some(last)) => println!(
+                    "  Node {}: {}",
+                    address,
+                    header
+                        .split(',')
+                        .zip(last.split(','))
+                        .map(|(name, value)| format!("{}={}", name, value))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+                _ => println!("  Node {}: no readings in {}", address, path.display()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A gateway being polled, reopened when it stops answering
+struct Link {
+    port: String,
+    baudrate: u32,
+    /// None while the gateway could not be reopened
+    driver: Option<GatewayDriver>,
+    /// Consecutive failed polls of any of its nodes
+    failures: u32,
+}
+
+/// A polled sensor node together with its log file
+struct Node {
+    address: usize,
+    failures: u32,
+    /// Outcome of the last successful poll's watering decision
+    watering: bool,
+    anomalies: AnomalyDetector,
+    /// SHA-256 of the image the node was last updated with, as the inventory has it
+    firmware: Option<String>,
+    log_path: PathBuf,
+    /// None when the readings are only printed
+    log: Option<File>,
+}
+
+impl Node {
+    fn new(config: &Config, address: usize, log_path: &Path, log: bool) -> Result<Node> {
+        let header = format!("time,{},moisture,pop,water", config.zone_labels.join(","));
+        let log = match (log, log_path.exists()) {
+            (false, _) => None,
+            (true, true) => {
+                // rows of a different zone count would not line up with the header
+                let existing =
+                    std::fs::read_to_string(log_path).context("Failed to read output file")?;
+                let columns = existing.lines().next().map_or(0, |h| h.split(',').count());
+                if columns != 0 && columns != header.split(',').count() {
+                    return Err(anyhow!(
+                        "{} was written for a different number of zones, move it aside",
+                        log_path.display()
+                    ));
+                }
+                let f = OpenOptions::new()
+                    .append(true)
+                    .open(log_path)
+                    .context("Failed to open output file")?;
+                Some(f)
+            }
+            (true, false) => {
+                let mut f = File::create(log_path).context("Failed to create output file")?;
+                f.write_all(format!("{}\n", header).as_bytes())?;
+                Some(f)
+            }
+        };
+        Ok(Node {
+            address,
+            failures: 0,
+            watering: false,
+            anomalies: AnomalyDetector::new(config.anomaly.clone(), config.zone_labels.len()),
+            firmware: None,
+            log_path: log_path.to_owned(),
+            log,
+        })
+    }
+
+    /// Rolls log rows older than `cutoff` into the hourly log and reopens the trimmed log
+    fn compact(&mut self, cutoff: NaiveDateTime) -> Result<()> {
+        let removed = aggregate::compact(&self.log_path, cutoff)?;
+        if removed > 0 {
+            let f = OpenOptions::new()
+                .append(true)
+                .open(&self.log_path)
+                .context("Failed to reopen output file")?;
+            self.log = Some(f);
+            info!(
+                "Node {}: rolled {} log rows up into {}",
+                self.address,
+                removed,
+                aggregate::hourly_path(&self.log_path).display()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Set by SIGINT or SIGTERM, the monitor closes the valves and exits after the current poll
+static STOP: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn stop_on_signal() {
+    extern "C" fn stop(_: libc::c_int) {
+        STOP.store(true, Ordering::Relaxed);
+    }
+    // Safety: the handler only touches an atomic
+    unsafe {
+        libc::signal(libc::SIGINT, stop as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, stop as *const () as libc::sighandler_t);
+    }
+}
+
+/// State shared by the polling of all nodes
+struct Reader {
+    config: Config,
+    output: OutputFormat,
+    weather: Weather,
+    notifier: Notifier,
+    sprinkler: Option<OpenSprinkler>,
+    failure_budget: u32,
+    /// Latest plausible normalized reading per node and channel, feeding remote probes
+    probes: HashMap<(usize, usize), (Instant, f64)>,
+    pws: Option<PwsUploader>,
+    /// Latest plausible moisture per node and zone label, for the weather station
+    zone_moisture: HashMap<(usize, String), f64>,
+}
+
+impl Reader {
+    fn new(config: Config, output: OutputFormat) -> Reader {
+        Reader {
+            weather: Weather::new(config.latitude, config.longitude, config.weather.clone()),
+            notifier: Notifier::new(config.notify_url.clone()),
+            sprinkler: config.opensprinkler.clone().map(|o| {
+                OpenSprinkler::new(
+                    o,
+                    config.zones.clone(),
+                    config.data_dir.join("water_usage.json"),
+                )
+            }),
+            failure_budget: config.failure_budget.max(1),
+            probes: HashMap::new(),
+            pws: config.pws.clone().map(PwsUploader::new),
+            zone_moisture: HashMap::new(),
+            output,
+            config,
+        }
+    }
+
+    /// Logs the water budgets of the week along with the metrics report
+    fn log_report(&mut self) {
+        let now = Utc::now().with_timezone(&self.config.timezone);
+        if let Some(sprinkler) = self.sprinkler.as_mut() {
+            sprinkler.log_budgets(&now);
+        }
+        metrics::log_report();
+    }
+
+    /// Failed uploads are only logged and retried at the next interval
+    fn upload_to_pws(&mut self) {
+        if let Some(pws) = self.pws.as_mut().filter(|p| p.due()) {
+            if let Err(e) = pws.upload(&self.zone_moisture) {
+                warn!("Failed to upload to the weather station: {:#}", e);
+            }
+        }
+    }
+
+    /// Picks up the node's firmware from the inventory, logging it the first time and whenever
+    /// an update changed it
+    fn check_firmware(&self, node: &mut Node) {
+        let firmware = match Inventory::load(&self.config.inventory) {
+            Ok(inventory) => inventory
+                .nodes
+                .get(&node.address)
+                .and_then(|n| n.firmware_sha256.clone()),
+            Err(e) => {
+                warn!("{:#}", e);
+                return;
+            }
+        };
+        if firmware != node.firmware {
+            match (&node.firmware, &firmware) {
+                (None, Some(new)) => info!("Node {}: firmware {}", node.address, new),
+                (Some(old), Some(new)) => info!(
+                    "Node {}: firmware changed from {} to {}",
+                    node.address, old, new
+                ),
+                (_, None) => info!("Node {}: firmware unknown", node.address),
+            }
+            node.firmware = firmware;
+        }
+    }
 
-    loop {
-        gateway.write(HostPacket::SoilSensor(SoilSensorRequest {
-            destination_address: args.destination_address,
-        }))?;
-        match gateway.read_with_timeout(Duration::from_secs(1)) {
-            Ok(resp) => match resp {
-                GatewayPacket::SoilSensorMoisture(s) => {
-                    println!("{:?}", s);
-                    let pop = weather.get_precipitation_probability()?;
-                    let watering = figure_out_watering(&config, s, pop);
-                    output_path.write_all(
-                        format!(
-                            "{},{},{},{},{},{},{},{}\n",
-                            Local::now().format("%y-%m-%d %H:%M.%S"),
-                            s[0],
-                            s[1],
-                            s[2],
-                            s[3],
-                            (watering.moisture * 100.0).round() as u16,
-                            (pop * 100.0).round() as u16,
-                            watering.watering as u8
-                        )
-                        .as_bytes(),
-                    )?;
+    /// Counts failed polls on the link, after `gateway_restart_after` in a row the gateway is
+    /// pinged and, when it does not answer, reopened, which finds an `auto` port anew
+    fn watch_link(&mut self, link: &mut Link, polled: bool) {
+        if polled {
+            link.failures = 0;
+            return;
+        }
+        link.failures += 1;
+        let limit = self.config.gateway_restart_after;
+        if limit == 0 || link.failures < limit {
+            return;
+        }
+        if link.driver.as_mut().is_some_and(|d| d.ping().is_ok()) {
+            // the gateway is fine, its nodes are out of reach
+            link.failures = 0;
+            return;
+        }
+        // the port has to be released before it can be opened again
+        link.driver = None;
+        let reopened = GatewayDriver::new(&link.port, link.baudrate).and_then(|mut driver| {
+            driver.ping()?;
+            Ok(driver)
+        });
+        match reopened {
+            Ok(driver) => {
+                metrics::increment("reader.gateway_restarts");
+                self.notifier.send(&format!(
+                    "Gateway {}: reopened after {} failed polls",
+                    link.port, link.failures
+                ));
+                link.driver = Some(driver);
+                link.failures = 0;
+            }
+            Err(e) => warn!("Gateway {}: failed to reopen: {:#}", link.port, e),
+        }
+    }
+
+    /// Reads the node and acts on the reading, anything failing on the way counts against the
+    /// failure budget rather than ending the monitor
+    fn poll(&mut self, mut gateway: Option<&mut GatewayDriver>, node: &mut Node) {
+        self.check_firmware(node);
+        self.enforce_run_limits(node);
+        let config = &self.config;
+        let retry = RetryPolicy {
+            retries: config.poll_retries,
+            backoff: Duration::from_millis(config.retry_backoff_ms),
+        };
+        let (reading, attempts) = retry.run(|attempt| {
+            let result = match gateway.as_mut() {
+                Some(gateway) => gateway.soil_sensor(node.address),
+                None => Err(anyhow!("The gateway is closed")),
+            };
+            if let (Err(e), true) = (&result, attempt <= retry.retries) {
+                info!(
+                    "Node {}: attempt {}/{} failed: {:#}",
+                    node.address,
+                    attempt,
+                    retry.retries + 1,
+                    e
+                );
+            }
+            result
+        });
+        metrics::increment("reader.polls");
+        metrics::observe("reader.poll_attempts", attempts as f64);
+        match reading.and_then(|s| self.record(node, &s)) {
+            Ok(()) => {
+                if attempts > 1 {
+                    info!("Node {}: read on attempt {}", node.address, attempts);
                 }
-                p => {
-                    eprintln!("Unexpected response: {:?}", p);
+                if node.failures >= self.failure_budget {
+                    self.notifier.send(&format!(
+                        "Node {}: sensor readings recovered after {} failed polls",
+                        node.address, node.failures
+                    ));
                 }
-            },
-            Err(_) => {
-                eprintln!("Response timeout");
+                node.failures = 0;
+            }
+            Err(e) => {
+                node.failures += 1;
+                metrics::increment("reader.poll_failures");
+                warn!(
+                    "Node {}: poll failed after {} attempts ({} in a row): {:#}",
+                    node.address, attempts, node.failures, e
+                );
+                if node.failures == self.failure_budget {
+                    self.notifier.send(&format!(
+                        "Node {}: {} consecutive sensor polls failed, watering is disabled until readings recover",
+                        node.address, node.failures
+                    ));
+                }
+                if node.failures >= self.failure_budget {
+                    self.degrade(node);
+                }
+            }
+        }
+    }
+
+    /// Closes stations open for longer than their zone allows in one go, whatever the node
+    /// decides and whether its sensor answers
+    fn enforce_run_limits(&mut self, node: &Node) {
+        let Some(sprinkler) = self.sprinkler.as_mut() else {
+            return;
+        };
+        let now = Utc::now().with_timezone(&self.config.timezone);
+        match sprinkler.enforce(node.address, &now) {
+            Ok(cutoffs) => self.notify_cutoffs(node, cutoffs),
+            Err(e) => self.notifier.send(&format!(
+                "Node {}: failed to close OpenSprinkler stations: {:#}",
+                node.address, e
+            )),
+        }
+    }
+
+    fn notify_cutoffs(&self, node: &Node, cutoffs: Vec<Cutoff>) {
+        for cutoff in cutoffs {
+            self.notifier.send(&format!(
+                "Node {}: closed OpenSprinkler station {} of {} after {} minutes of continuous watering",
+                node.address, cutoff.station, cutoff.zone, cutoff.minutes
+            ));
+        }
+    }
+
+    /// Decides on watering from a reading, acts on it and logs it
+    fn record(&mut self, node: &mut Node, s: &[u16; SENSOR_CHANNELS]) -> Result<()> {
+        let config = &self.config;
+        let now = Utc::now().with_timezone(&config.timezone);
+        // a missing forecast is the weather's failure, not the sensor's, so it does not count
+        // against the poll failure budget
+        let forecast = match self.weather.get_forecast(now.month0() as usize) {
+            Ok(forecast) => Some(forecast),
+            Err(e) => {
+                warn!(
+                    "Node {}: not watering without a forecast: {:#}",
+                    node.address, e
+                );
+                None
+            }
+        };
+        let raw = &s[..config.zone_labels.len()];
+        let zones = normalize(config, raw);
+        for (zone, transition) in node.anomalies.update(&zones) {
+            let label = &config.zone_labels[zone];
+            self.notifier.send(&match transition {
+                Transition::Flagged { from, to } => format!(
+                    "Node {}: implausible moisture jump in {} from {:.0}% to {:.0}%, ignoring the zone",
+                    node.address,
+                    label,
+                    from * 100.0,
+                    to * 100.0
+                ),
+                Transition::Cleared => {
+                    format!("Node {}: {} readings are plausible again", node.address, label)
+                }
+            });
+        }
+        for (channel, moisture) in zones.iter().enumerate() {
+            if !node.anomalies.flagged()[channel] {
+                self.probes
+                    .insert((node.address, channel), (Instant::now(), *moisture));
+            }
+        }
+        let probes = &self.probes;
+        let combined = combine_probes(config, &zones, node.anomalies.flagged(), |node, channel| {
+            probes
+                .get(&(node, channel))
+                .filter(|(at, _)| at.elapsed() < PROBE_MAX_AGE)
+                .map(|(_, moisture)| *moisture)
+        });
+        let mut watering = figure_out_watering(
+            config,
+            &now,
+            zones,
+            node.anomalies.flagged(),
+            combined,
+            node.watering,
+            forecast.as_ref(),
+        );
+        if let (true, Some(sprinkler)) = (watering.watering, self.sprinkler.as_mut()) {
+            if sprinkler.budget_exhausted(node.address, &now) {
+                watering.watering = false;
+                watering.reason = Reason::BudgetExhausted;
+                metrics::increment("reader.budget_refusals");
+            } else if sprinkler.runs_exhausted(node.address, &now) {
+                watering.watering = false;
+                watering.reason = Reason::RunLimitReached;
             }
         }
+        node.watering = watering.watering;
+        for zone in watering.combined.iter() {
+            let key = (node.address, zone.label.clone());
+            match zone.plausible {
+                true => self.zone_moisture.insert(key, zone.moisture),
+                false => self.zone_moisture.remove(&key),
+            };
+        }
+        print_reading(
+            config,
+            self.output,
+            node,
+            &now,
+            raw,
+            forecast.as_ref(),
+            &watering,
+        );
+        match (watering.watering, self.sprinkler.as_mut()) {
+            (true, Some(sprinkler)) => {
+                match sprinkler.water(node.address, &now, watering.duration_factor) {
+                    Ok(cutoffs) => self.notify_cutoffs(node, cutoffs),
+                    Err(e) => self.notifier.send(&format!(
+                        "Node {}: failed to start OpenSprinkler stations: {:#}",
+                        node.address, e
+                    )),
+                }
+            }
+            (false, Some(sprinkler)) => {
+                if let Err(e) = sprinkler.idle(node.address) {
+                    warn!("Node {}: {:#}", node.address, e);
+                }
+            }
+            _ => {}
+        }
+        if let Some(log) = node.log.as_mut() {
+            log.write_all(
+                format!(
+                    "{},{},{},{},{}\n",
+                    now.format(TIME_FORMAT),
+                    raw.iter()
+                        .map(|m| m.to_string())
+                        .collect::<Vec<String>>()
+                        .join(","),
+                    (watering.moisture * 100.0).round() as u16,
+                    forecast.map_or(String::new(), |f| {
+                        ((f.precipitation_probability * 100.0).round() as u16).to_string()
+                    }),
+                    watering.watering as u8
+                )
+                .as_bytes(),
+            )
+            .context("Failed to log the reading")?;
+        }
+        Ok(())
+    }
+
+    /// Degraded mode, keeps recording an explicit fail-safe decision
+    fn degrade(&mut self, node: &mut Node) {
+        let config = &self.config;
+        node.watering = false;
+        if let Some(Err(e)) = self.sprinkler.as_mut().map(|s| s.idle(node.address)) {
+            warn!("Node {}: {:#}", node.address, e);
+        }
+        let now = Utc::now().with_timezone(&config.timezone);
+        if self.output == OutputFormat::Json {
+            println!(
+                "{}",
+                json!({
+                    "timestamp": now.to_rfc3339(),
+                    "node": node.address,
+                    "degraded": true,
+                    "watering": false,
+                })
+            );
+        }
+        let Some(log) = node.log.as_mut() else {
+            return;
+        };
+        let logged = log.write_all(
+            format!(
+                "{},{},,,0\n",
+                now.format(TIME_FORMAT),
+                vec![""; config.zone_labels.len()].join(",")
+            )
+            .as_bytes(),
+        );
+        if let Err(e) = logged {
+            warn!(
+                "Node {}: failed to log the fail-safe decision: {}",
+                node.address, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Zones calibrated to 0..100 raw, watering below 30% between 6:00 and 18:00 UTC
+    fn config(zone_labels: &[&str], zones: serde_json::Value) -> Config {
+        serde_json::from_value(json!({
+            "timezone": "UTC",
+            "latitude": 50.0755,
+            "longitude": 14.4378,
+            "sensor_cal_low": vec![0; zone_labels.len()],
+            "sensor_cal_high": vec![100; zone_labels.len()],
+            "zone_labels": zone_labels,
+            "zones": zones,
+            "moisture_threshold": 30,
+            "precipitation_threshold": 50,
+            "day_start": 6,
+            "day_end": 18,
+        }))
+        .unwrap()
+    }
+
+    fn forecast(rain: f64) -> Forecast {
+        Forecast {
+            precipitation_probability: rain,
+            wind_speed: 0.0,
+            max_temperature: 20.0,
+            precipitation: 0.0,
+            fallback: false,
+        }
+    }
+
+    fn decide(config: &Config, zones: &[f64], was_watering: bool, rain: f64, hour: u32) -> Reason {
+        let anomalies = vec![false; zones.len()];
+        let combined = combine_probes(config, zones, &anomalies, |_, _| None);
+        let now = Tz::UTC.with_ymd_and_hms(2024, 6, 1, hour, 0, 0).unwrap();
+        let (zones, forecast) = (zones.to_vec(), forecast(rain));
+        let forecast = Some(&forecast);
+        figure_out_watering(
+            config,
+            &now,
+            zones,
+            &anomalies,
+            combined,
+            was_watering,
+            forecast,
+        )
+        .reason
+    }
+
+    fn moisture(readings: &[ZoneReading]) -> Vec<(&str, f64, bool)> {
+        readings
+            .iter()
+            .map(|r| {
+                (
+                    r.label.as_str(),
+                    (r.moisture * 100.0).round() / 100.0,
+                    r.plausible,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn probes_sharing_a_label_make_one_zone() {
+        let config = config(&["bed", "bed", "lawn"], json!({}));
+        let combined = combine_probes(&config, &[0.2, 0.4, 0.9], &[false, true, false], |_, _| {
+            None
+        });
+        assert_eq!(
+            moisture(&combined),
+            [("bed", 0.2, true), ("lawn", 0.9, true)]
+        );
+        // implausible probes still give a value, but the zone is not plausible
+        let combined = combine_probes(&config, &[0.2, 0.4, 0.9], &[true, true, false], |_, _| None);
+        assert_eq!(
+            moisture(&combined),
+            [("bed", 0.3, false), ("lawn", 0.9, true)]
+        );
+    }
+
+    #[test]
+    fn remote_probes_count_towards_their_zone() {
+        let zones = json!({
+            "lawn": {"remote_probes": [{"node": 7, "channel": 1}], "aggregate": "min"}
+        });
+        let config = config(&["lawn"], zones);
+        let remote = |node, channel| (node == 7 && channel == 1).then_some(0.5);
+        let combined = combine_probes(&config, &[0.9], &[false], remote);
+        assert_eq!(moisture(&combined), [("lawn", 0.5, true)]);
+        // a stale remote probe is left out
+        let combined = combine_probes(&config, &[0.9], &[false], |_, _| None);
+        assert_eq!(moisture(&combined), [("lawn", 0.9, true)]);
+    }
+
+    #[test]
+    fn watering_needs_dry_soil_inside_the_window_and_no_rain() {
+        let config = config(&["zone1"], json!({}));
+        assert_eq!(decide(&config, &[0.2], false, 0.0, 12), Reason::Watering);
+        assert_eq!(decide(&config, &[0.4], false, 0.0, 12), Reason::MoistEnough);
+        assert_eq!(
+            decide(&config, &[0.2], false, 0.0, 20),
+            Reason::OutsideWindow
+        );
+        assert_eq!(
+            decide(&config, &[0.2], false, 0.6, 12),
+            Reason::RainExpected
+        );
+    }
+
+    #[test]
+    fn watering_continues_through_the_hysteresis() {
+        let config = config(&["zone1"], json!({"zone1": {"hysteresis": 10}}));
+        assert_eq!(
+            decide(&config, &[0.35], false, 0.0, 12),
+            Reason::MoistEnough
+        );
+        assert_eq!(decide(&config, &[0.35], true, 0.0, 12), Reason::Watering);
+        assert_eq!(decide(&config, &[0.45], true, 0.0, 12), Reason::MoistEnough);
+    }
+
+    #[test]
+    fn watering_is_off_without_a_plausible_zone() {
+        let config = config(&["zone1"], json!({}));
+        let combined = combine_probes(&config, &[0.1], &[true], |_, _| None);
+        let now = Tz::UTC.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let forecast = Some(&forecast(0.0));
+        let result =
+            figure_out_watering(&config, &now, vec![0.1], &[true], combined, false, forecast);
+        assert_eq!(result.reason, Reason::NoPlausibleZones);
+        assert!(!result.watering);
+    }
 
-        sleep(Duration::from_secs(15));
+    #[test]
+    fn watering_is_off_without_a_forecast() {
+        let config = config(&["zone1"], json!({}));
+        let combined = combine_probes(&config, &[0.2], &[false], |_, _| None);
+        let now = Tz::UTC.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let result = figure_out_watering(&config, &now, vec![0.2], &[false], combined, false, None);
+        assert_eq!(result.reason, Reason::NoForecast);
+        assert!(!result.watering);
     }
 }
@@ -0,0 +1,131 @@
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+
+/// Layout of config.json this reader understands, configs without a version are version 1
+pub const CONFIG_VERSION: u64 = 2;
+
+/// Version the config file was written for
+fn version(file: &Value) -> Result<u64> {
+    match file.get("version") {
+        None => Ok(1),
+        Some(v) => v
+            .as_u64()
+            .ok_or(anyhow!("\"version\" must be a number, it is {}", v)),
+    }
+}
+
+/// Brings a config file written for an older reader up to `CONFIG_VERSION`, returns the
+/// version it was written for. Fails for files of a newer reader rather than guessing. Keys
+/// keep their order so that a migrated file diffs cleanly against the original, the caller
+/// only writes it back when the returned version is older.
+pub fn migrate(file: &mut Value) -> Result<u64> {
+    let from = version(file)?;
+    if from > CONFIG_VERSION {
+        return Err(anyhow!(
+            "The config file is version {}, this reader understands up to {}",
+            from,
+            CONFIG_VERSION
+        ));
+    }
+    let root = file
+        .as_object_mut()
+        .ok_or(anyhow!("The config file must hold an object"))?;
+    if from < 2 {
+        for site in sites(root)? {
+            rename_hour_keys(site)?;
+        }
+    }
+    match root.get_mut("version") {
+        Some(version) => *version = CONFIG_VERSION.into(),
+        None => {
+            let keys = std::mem::take(root);
+            root.insert("version".to_owned(), CONFIG_VERSION.into());
+            root.extend(keys);
+        }
+    }
+    Ok(from)
+}
+
+/// The site configs of the file, the root itself when it describes a single site
+fn sites(root: &mut Map<String, Value>) -> Result<Vec<&mut Map<String, Value>>> {
+    if !root.contains_key("sites") {
+        return Ok(vec![root]);
+    }
+    root.get_mut("sites")
+        .and_then(Value::as_object_mut)
+        .ok_or(anyhow!("\"sites\" must be an object"))?
+        .iter_mut()
+        .map(|(name, site)| {
+            site.as_object_mut()
+                .ok_or(anyhow!("Site \"{}\" must be an object", name))
+        })
+        .collect()
+}
+
+/// Version 1 named the watering window `day_start_hour` and `day_end_hour`
+fn rename_hour_keys(site: &mut Map<String, Value>) -> Result<()> {
+    let renames = [("day_start_hour", "day_start"), ("day_end_hour", "day_end")];
+    if let Some((old, new)) = renames
+        .iter()
+        .find(|(old, new)| site.contains_key(*old) && site.contains_key(*new))
+    {
+        return Err(anyhow!("The config sets both {} and {}", old, new));
+    }
+    // renamed in place rather than removed and inserted, which would move them to the end
+    *site = std::mem::take(site)
+        .into_iter()
+        .map(
+            |(key, value)| match renames.iter().find(|(old, _)| *old == key) {
+                Some((_, new)) => (new.to_string(), value),
+                None => (key, value),
+            },
+        )
+        .collect();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn migration_keeps_the_key_order() {
+        let mut file = json!({"timezone": "UTC", "day_start_hour": 6, "day_end_hour": 18});
+        assert_eq!(migrate(&mut file).unwrap(), 1);
+        assert_eq!(
+            serde_json::to_string(&file).unwrap(),
+            r#"{"version":2,"timezone":"UTC","day_start":6,"day_end":18}"#
+        );
+    }
+
+    #[test]
+    fn migration_renames_the_hour_keys_of_every_site() {
+        let mut file = json!({"sites": {"a": {"day_start_hour": 6}, "b": {"day_end_hour": 18}}});
+        assert_eq!(migrate(&mut file).unwrap(), 1);
+        assert_eq!(
+            file,
+            json!({"version": 2, "sites": {"a": {"day_start": 6}, "b": {"day_end": 18}}})
+        );
+    }
+
+    #[test]
+    fn current_files_are_left_alone() {
+        let mut file = json!({"version": 2, "day_start": 6});
+        assert_eq!(migrate(&mut file).unwrap(), CONFIG_VERSION);
+        assert_eq!(file, json!({"version": 2, "day_start": 6}));
+    }
+
+    #[test]
+    fn migration_refuses_what_it_cannot_tell_apart() {
+        for mut file in [
+            json!({"version": 3}),
+            json!({"version": "2"}),
+            json!({"day_start_hour": 6, "day_start": 7}),
+            json!({"sites": {"a": 1}}),
+            json!([]),
+        ] {
+            assert!(migrate(&mut file).is_err(), "{}", file);
+        }
+    }
+}
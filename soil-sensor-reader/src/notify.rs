@@ -0,0 +1,33 @@
+use crate::{transient_http_error, HTTP_RETRY};
+use serde_json::json;
+use tracing::warn;
+
+/// Raises alerts in the log and, when configured, posts them to a webhook
+pub struct Notifier {
+    url: Option<String>,
+}
+
+impl Notifier {
+    pub fn new(url: Option<String>) -> Self {
+        Self { url }
+    }
+
+    /// Delivery failures are only logged, an unreachable webhook must not stop the reader
+    pub fn send(&self, message: &str) {
+        warn!("ALERT: {}", message);
+        if let Some(url) = &self.url {
+            let client = reqwest::blocking::Client::new();
+            let (result, _) = HTTP_RETRY.run_if(transient_http_error, |_| {
+                client
+                    .post(url)
+                    .json(&json!({ "message": message }))
+                    .send()
+                    .and_then(|r| r.error_for_status())
+                    .map_err(anyhow::Error::from)
+            });
+            if let Err(e) = result {
+                warn!("Failed to deliver notification: {:#}", e);
+            }
+        }
+    }
+}
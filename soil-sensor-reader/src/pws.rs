@@ -0,0 +1,103 @@
+use crate::{transient_http_error, HTTP_RETRY};
+use anyhow::{anyhow, Result};
+use lora_host_common::secret::Secret;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Soil moisture fields of the upload protocol, `soilmoisture`, `soilmoisture2`, ...
+pub const MOISTURE_FIELDS: usize = 4;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PwsConfig {
+    pub station_id: String,
+    pub key: Secret,
+    /// Upload endpoint speaking the Weather Underground protocol
+    #[serde(default = "default_url")]
+    pub url: String,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// Zones reported as `soilmoisture`, `soilmoisture2`, ... in this order
+    pub zones: Vec<PwsZone>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PwsZone {
+    pub node: usize,
+    pub zone: String,
+}
+
+fn default_url() -> String {
+    "https://weatherstation.wunderground.com/weatherstation/updateweatherstation.php".to_owned()
+}
+
+fn default_interval_secs() -> u64 {
+    300
+}
+
+/// Publishes the latest zone moisture to a personal weather station on a schedule
+pub struct PwsUploader {
+    config: PwsConfig,
+    uploaded_at: Option<Instant>,
+}
+
+impl PwsUploader {
+    pub fn new(config: PwsConfig) -> Self {
+        Self {
+            config,
+            uploaded_at: None,
+        }
+    }
+
+    pub fn due(&self) -> bool {
+        self.uploaded_at
+            .is_none_or(|at| at.elapsed() >= Duration::from_secs(self.config.interval_secs))
+    }
+
+    /// Uploads the zones found in `moisture`, keyed by node and zone label and normalized to
+    /// 0..1. Zones without a reading are left out, nothing is sent when none has one.
+    pub fn upload(&mut self, moisture: &HashMap<(usize, String), f64>) -> Result<()> {
+        self.uploaded_at = Some(Instant::now());
+        let fields = self
+            .config
+            .zones
+            .iter()
+            .enumerate()
+            .filter_map(|(i, z)| {
+                let name = match i {
+                    0 => "soilmoisture".to_owned(),
+                    _ => format!("soilmoisture{}", i + 1),
+                };
+                moisture
+                    .get(&(z.node, z.zone.clone()))
+                    .map(|m| (name, format!("{:.0}", m * 100.0)))
+            })
+            .collect::<Vec<(String, String)>>();
+        if fields.is_empty() {
+            return Ok(());
+        }
+
+        let client = reqwest::blocking::Client::new();
+        let (response, _) = HTTP_RETRY.run_if(transient_http_error, |_| {
+            Ok(client
+                .get(&self.config.url)
+                .query(&[
+                    ("ID", self.config.station_id.as_str()),
+                    ("PASSWORD", self.config.key.expose()),
+                    ("dateutc", "now"),
+                    ("action", "updateraw"),
+                ])
+                .query(&fields)
+                .send()
+                .and_then(|r| r.error_for_status())
+                .and_then(|r| r.text())
+                .map_err(reqwest::Error::without_url)?)
+        });
+        let response = response?;
+        // Weather Underground answers rejected uploads with 200 as well
+        match response.starts_with("INVALID") {
+            true => Err(anyhow!("Station upload refused: {}", response.trim())),
+            false => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use chrono::Duration;
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+/// A point in the day, either a fixed local time or an offset relative to sunrise/sunset.
+///
+/// Deserializes from an hour number (`6`), a local time (`"06:30"`) or a sun-relative
+/// expression (`"sunrise-1h"`, `"sunset+30m"`).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "DayTimeRepr", into = "DayTimeRepr")]
+pub enum DayTime {
+    Fixed(NaiveTime),
+    Sunrise(Duration),
+    Sunset(Duration),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum DayTimeRepr {
+    Hour(u32),
+    Text(String),
+}
+
+impl TryFrom<DayTimeRepr> for DayTime {
+    type Error = anyhow::Error;
+
+    fn try_from(value: DayTimeRepr) -> Result<Self> {
+        match value {
+            DayTimeRepr::Hour(h) => NaiveTime::from_hms_opt(h, 0, 0)
+                .map(DayTime::Fixed)
+                .ok_or(anyhow!("invalid hour {}", h)),
+            DayTimeRepr::Text(s) => s.parse(),
+        }
+    }
+}
+
+impl From<DayTime> for DayTimeRepr {
+    fn from(value: DayTime) -> Self {
+        let offset = |base: &str, d: Duration| match d.num_minutes() {
+            0 => base.to_owned(),
+            m if m % 60 == 0 => format!("{}{:+}h", base, m / 60),
+            m => format!("{}{:+}m", base, m),
+        };
+        DayTimeRepr::Text(match value {
+            DayTime::Fixed(t) => t.format("%H:%M").to_string(),
+            DayTime::Sunrise(d) => offset("sunrise", d),
+            DayTime::Sunset(d) => offset("sunset", d),
+        })
+    }
+}
+
+impl std::str::FromStr for DayTime {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if let Some(rest) = s.strip_prefix("sunrise") {
+            return Ok(DayTime::Sunrise(parse_offset(rest)?));
+        }
+        if let Some(rest) = s.strip_prefix("sunset") {
+            return Ok(DayTime::Sunset(parse_offset(rest)?));
+        }
+        NaiveTime::parse_from_str(s, "%H:%M")
+            .map(DayTime::Fixed)
+            .map_err(|e| anyhow!("invalid time of day \"{}\": {}", s, e))
+    }
+}
+
+/// Parses offsets like `+1h`, `-30m` or `+1h30m`, an empty string means no offset.
+fn parse_offset(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(Duration::zero());
+    }
+    let (sign, mut rest) = if let Some(rest) = s.strip_prefix('+') {
+        (1, rest)
+    } else if let Some(rest) = s.strip_prefix('-') {
+        (-1, rest)
+    } else {
+        return Err(anyhow!("offset \"{}\" must start with + or -", s));
+    };
+
+    let mut minutes = 0i64;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let value: i64 = rest[..digits]
+            .parse()
+            .map_err(|_| anyhow!("invalid offset \"{}\"", s))?;
+        let unit = match rest[digits..].chars().next() {
+            Some('h') => 60,
+            Some('m') => 1,
+            _ => return Err(anyhow!("offset \"{}\" needs an h or m unit", s)),
+        };
+        minutes = value
+            .checked_mul(unit)
+            .and_then(|value| minutes.checked_add(value))
+            .ok_or(anyhow!("offset \"{}\" out of range", s))?;
+        rest = &rest[digits + 1..];
+    }
+    Duration::try_minutes(sign * minutes).ok_or(anyhow!("offset \"{}\" out of range", s))
+}
+
+impl DayTime {
+    /// Resolves to a concrete time on the given local date, `None` when the sun does not
+    /// rise or set on that day (polar day/night).
+    pub fn resolve(
+        &self,
+        date: NaiveDate,
+        timezone: &Tz,
+        latitude: f64,
+        longitude: f64,
+    ) -> Option<DateTime<Tz>> {
+        match self {
+            DayTime::Fixed(t) => timezone.from_local_datetime(&date.and_time(*t)).earliest(),
+            DayTime::Sunrise(offset) => sun_events(date, latitude, longitude)
+                .map(|(rise, _)| rise.with_timezone(timezone) + *offset),
+            DayTime::Sunset(offset) => sun_events(date, latitude, longitude)
+                .map(|(_, set)| set.with_timezone(timezone) + *offset),
+        }
+    }
+}
+
+/// Computes sunrise and sunset in UTC for the given date using the sunrise equation,
+/// accurate to a minute or two which is plenty for scheduling.
+pub fn sun_events(
+    date: NaiveDate,
+    latitude: f64,
+    longitude: f64,
+) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let j2000 = NaiveDate::from_ymd_opt(2000, 1, 1)?;
+    // Julian date of the day's midnight, rounded up to the Julian cycle since J2000.0 whose
+    // solar noon falls on the date
+    let julian = 2451544.5 + (date - j2000).num_days() as f64;
+    let cycle = (julian - 2451545.0 + 0.0008).ceil();
+
+    let mean_solar_noon = cycle - longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * mean_solar_noon)
+        .rem_euclid(360.0)
+        .to_radians();
+    let center =
+        1.9148 * anomaly.sin() + 0.0200 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic_longitude = (anomaly.to_degrees() + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let transit = 2451545.0 + mean_solar_noon + 0.0053 * anomaly.sin()
+        - 0.0069 * (2.0 * ecliptic_longitude).sin();
+
+    let sin_declination = ecliptic_longitude.sin() * 23.4397f64.to_radians().sin();
+    let cos_declination = (1.0 - sin_declination * sin_declination).sqrt();
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = ((-0.833f64).to_radians().sin() - latitude.sin() * sin_declination)
+        / (latitude.cos() * cos_declination);
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees() / 360.0;
+
+    let to_utc =
+        |julian: f64| DateTime::from_timestamp(((julian - 2440587.5) * 86400.0).round() as i64, 0);
+    Some((to_utc(transit - hour_angle)?, to_utc(transit + hour_angle)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Within three minutes of the almanac, the equation is not meant to be more precise
+    fn assert_near(time: DateTime<Utc>, expected: &str) {
+        let expected = DateTime::parse_from_rfc3339(expected).unwrap();
+        let off = (time - expected.with_timezone(&Utc)).num_seconds().abs();
+        assert!(off <= 180, "{} is {} s off {}", time, off, expected);
+    }
+
+    #[test]
+    fn sun_events_match_the_almanac_in_prague() {
+        for (date, sunrise, sunset) in [
+            (
+                (2024, 6, 21),
+                "2024-06-21T04:51:00+02:00",
+                "2024-06-21T21:15:00+02:00",
+            ),
+            (
+                (2024, 12, 21),
+                "2024-12-21T07:59:00+01:00",
+                "2024-12-21T16:02:00+01:00",
+            ),
+        ] {
+            let date = NaiveDate::from_ymd_opt(date.0, date.1, date.2).unwrap();
+            let (rise, set) = sun_events(date, 50.0755, 14.4378).unwrap();
+            assert_near(rise, sunrise);
+            assert_near(set, sunset);
+        }
+    }
+
+    #[test]
+    fn offsets_add_up_their_units() {
+        assert_eq!(parse_offset("+1h30m").unwrap(), Duration::minutes(90));
+        assert_eq!(parse_offset("-30m").unwrap(), Duration::minutes(-30));
+        assert_eq!(parse_offset("").unwrap(), Duration::zero());
+        for overflowing in ["+153722867280912931h", "-200000000000000m"] {
+            let error = parse_offset(overflowing).unwrap_err().to_string();
+            assert!(error.contains("out of range"), "{}", error);
+        }
+    }
+}
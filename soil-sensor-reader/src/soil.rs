@@ -0,0 +1,220 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SoilType {
+    Sand,
+    Loam,
+    Clay,
+}
+
+/// Watering parameters suited to a soil type
+pub struct SoilProfile {
+    /// Moisture in percent below which watering starts
+    pub moisture_threshold: f64,
+    /// Percentage points above the threshold a zone has to reach before watering stops
+    pub hysteresis: f64,
+    /// Longest single station run in minutes
+    pub cycle_minutes: u32,
+    /// Pause between two runs of a station letting the water soak in, in minutes
+    pub soak_minutes: u32,
+}
+
+impl SoilType {
+    pub fn profile(self) -> SoilProfile {
+        match self {
+            // drains fast and holds little, long runs soak in right away
+            SoilType::Sand => SoilProfile {
+                moisture_threshold: 15.0,
+                hysteresis: 10.0,
+                cycle_minutes: 15,
+                soak_minutes: 10,
+            },
+            SoilType::Loam => SoilProfile {
+                moisture_threshold: 25.0,
+                hysteresis: 10.0,
+                cycle_minutes: 10,
+                soak_minutes: 20,
+            },
+            // takes water slowly, short runs with long pauses avoid runoff
+            SoilType::Clay => SoilProfile {
+                moisture_threshold: 35.0,
+                hysteresis: 8.0,
+                cycle_minutes: 5,
+                soak_minutes: 40,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlantPreset {
+    Lawn,
+    Tomatoes,
+    Succulents,
+}
+
+/// Moisture range and watering frequency a plant is happy with
+pub struct PlantProfile {
+    /// Moisture in percent below which watering starts
+    pub moisture_min: f64,
+    /// Moisture in percent at which watering stops
+    pub moisture_max: f64,
+    pub max_runs_per_day: u32,
+}
+
+impl PlantPreset {
+    pub fn profile(self) -> PlantProfile {
+        match self {
+            PlantPreset::Lawn => PlantProfile {
+                moisture_min: 25.0,
+                moisture_max: 45.0,
+                max_runs_per_day: 3,
+            },
+            PlantPreset::Tomatoes => PlantProfile {
+                moisture_min: 40.0,
+                moisture_max: 60.0,
+                max_runs_per_day: 4,
+            },
+            // rot when kept wet, an occasional drink is all they want
+            PlantPreset::Succulents => PlantProfile {
+                moisture_min: 10.0,
+                moisture_max: 20.0,
+                max_runs_per_day: 1,
+            },
+        }
+    }
+}
+
+/// How the probes of a zone are combined into the moisture the decision is based on
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregate {
+    #[default]
+    Mean,
+    Median,
+    /// The driest probe, e.g. the shallow one of a deep and shallow pair
+    Min,
+}
+
+impl Aggregate {
+    pub fn apply(self, values: &[f64]) -> Option<f64> {
+        if values.is_empty() {
+            return None;
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        Some(match self {
+            Aggregate::Mean => sorted.iter().sum::<f64>() / sorted.len() as f64,
+            Aggregate::Median => match sorted.len() % 2 {
+                1 => sorted[sorted.len() / 2],
+                _ => (sorted[sorted.len() / 2 - 1] + sorted[sorted.len() / 2]) / 2.0,
+            },
+            Aggregate::Min => sorted[0],
+        })
+    }
+}
+
+/// A probe channel of another node counted towards a zone
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteProbe {
+    pub node: usize,
+    /// Zero based channel of the node's sensor
+    pub channel: usize,
+}
+
+/// Per-zone settings. Fields given explicitly win over the plant preset, which in turn decides
+/// the moisture target over the soil profile.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneSettings {
+    #[serde(default)]
+    pub soil: Option<SoilType>,
+    #[serde(default)]
+    pub plant: Option<PlantPreset>,
+    #[serde(default)]
+    pub moisture_threshold: Option<f64>,
+    #[serde(default)]
+    pub hysteresis: Option<f64>,
+    #[serde(default)]
+    pub cycle_minutes: Option<u32>,
+    #[serde(default)]
+    pub soak_minutes: Option<u32>,
+    /// Station runs allowed per day, cycles split by soaking count separately
+    #[serde(default)]
+    pub max_runs_per_day: Option<u32>,
+    /// Most minutes a station may water while its zone keeps asking for water without a break,
+    /// the valve is closed and an alert raised once they are used up
+    #[serde(default)]
+    pub max_continuous_minutes: Option<u32>,
+    /// Combines the zone's probes, the channels sharing its label and `remote_probes`
+    #[serde(default)]
+    pub aggregate: Aggregate,
+    /// Probes on other nodes polled by this reader that also measure the zone
+    #[serde(default)]
+    pub remote_probes: Vec<RemoteProbe>,
+}
+
+impl ZoneSettings {
+    fn pick<T>(&self, explicit: Option<T>, from_profile: impl Fn(SoilProfile) -> T) -> Option<T> {
+        explicit.or(self.soil.map(|s| from_profile(s.profile())))
+    }
+
+    pub fn moisture_threshold(&self) -> Option<f64> {
+        let plant = self.plant.map(|p| p.profile().moisture_min);
+        self.pick(self.moisture_threshold.or(plant), |p| p.moisture_threshold)
+    }
+
+    pub fn hysteresis(&self) -> Option<f64> {
+        let plant = self
+            .plant
+            .map(|p| p.profile())
+            .map(|p| p.moisture_max - p.moisture_min);
+        self.pick(self.hysteresis.or(plant), |p| p.hysteresis)
+    }
+
+    pub fn cycle_minutes(&self) -> Option<u32> {
+        self.pick(self.cycle_minutes, |p| p.cycle_minutes)
+    }
+
+    pub fn soak_minutes(&self) -> Option<u32> {
+        self.pick(self.soak_minutes, |p| p.soak_minutes)
+    }
+
+    pub fn max_runs_per_day(&self) -> Option<u32> {
+        self.max_runs_per_day
+            .or(self.plant.map(|p| p.profile().max_runs_per_day))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregates_combine_probes() {
+        let probes = [0.4, 0.1, 0.3, 0.2];
+        assert_eq!(Aggregate::Mean.apply(&probes), Some(0.25));
+        assert_eq!(Aggregate::Median.apply(&probes), Some(0.25));
+        assert_eq!(Aggregate::Median.apply(&probes[..3]), Some(0.3));
+        assert_eq!(Aggregate::Min.apply(&probes), Some(0.1));
+        assert_eq!(Aggregate::Mean.apply(&[]), None);
+    }
+
+    #[test]
+    fn explicit_settings_win_over_the_plant_and_the_soil() {
+        let mut zone = ZoneSettings {
+            soil: Some(SoilType::Clay),
+            ..Default::default()
+        };
+        assert_eq!(zone.moisture_threshold(), Some(35.0));
+        zone.plant = Some(PlantPreset::Lawn);
+        assert_eq!(zone.moisture_threshold(), Some(25.0));
+        assert_eq!(zone.hysteresis(), Some(20.0));
+        zone.moisture_threshold = Some(30.0);
+        assert_eq!(zone.moisture_threshold(), Some(30.0));
+        assert_eq!(ZoneSettings::default().moisture_threshold(), None);
+    }
+}
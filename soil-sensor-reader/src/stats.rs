@@ -0,0 +1,123 @@
+use crate::aggregate::{self, HOURLY_TIME_FORMAT, TIME_FORMAT};
+use anyhow::{anyhow, Context, Result};
+use chrono::prelude::*;
+use std::path::Path;
+
+/// Moisture extremes and average of a zone in percent
+pub struct ZoneStats {
+    pub label: String,
+    pub min: f64,
+    pub max: f64,
+    sum: f64,
+    samples: u64,
+}
+
+impl ZoneStats {
+    pub fn average(&self) -> Option<f64> {
+        match self.samples {
+            0 => None,
+            n => Some(self.sum / n as f64),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct NodeStats {
+    pub zones: Vec<ZoneStats>,
+    /// Polls with readings
+    pub polls: u64,
+    /// Polls that decided to water
+    pub watering: u64,
+    /// Failed polls logged in degraded mode, these are not kept in the hourly log
+    pub degraded: u64,
+}
+
+/// Summarizes a node's raw and hourly log between `from` and `to`, both inclusive. `percent`
+/// maps a channel index and its raw reading to moisture in percent, None for an unknown channel.
+/// Rows rolled up into the hourly log contribute their averages, so extremes within an hour are
+/// lost.
+pub fn node(
+    log_path: &Path,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    percent: impl Fn(usize, f64) -> Option<f64>,
+) -> Result<NodeStats> {
+    let mut stats = NodeStats::default();
+    let in_range = |time: NaiveDateTime| {
+        from.is_none_or(|from| time.date() >= from) && to.is_none_or(|to| time.date() <= to)
+    };
+    for (path, time_format, hourly) in [
+        (aggregate::hourly_path(log_path), HOURLY_TIME_FORMAT, true),
+        (log_path.to_owned(), TIME_FORMAT, false),
+    ] {
+        if !path.exists() {
+            continue;
+        }
+        let log = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut lines = log.lines();
+        let header = lines
+            .next()
+            .ok_or(anyhow!("{} is empty", path.display()))?
+            .split(',')
+            .collect::<Vec<&str>>();
+        // time, the zones, moisture, pop, water and in the hourly log samples
+        let zones = header.len().saturating_sub(4 + hourly as usize);
+        if zones == 0 {
+            return Err(anyhow!("Unexpected header in {}", path.display()));
+        }
+        for label in &header[1..=zones] {
+            if !stats.zones.iter().any(|z| z.label == *label) {
+                stats.zones.push(ZoneStats {
+                    label: label.to_string(),
+                    min: f64::INFINITY,
+                    max: f64::NEG_INFINITY,
+                    sum: 0.0,
+                    samples: 0,
+                });
+            }
+        }
+
+        for line in lines {
+            let fields = line.split(',').collect::<Vec<&str>>();
+            if fields.len() != header.len() {
+                continue;
+            }
+            match NaiveDateTime::parse_from_str(fields[0], time_format) {
+                Ok(time) if in_range(time) => {}
+                _ => continue,
+            }
+            let water = fields[zones + 3].parse::<u64>().unwrap_or(0);
+            let samples = match hourly {
+                true => fields[zones + 4].parse::<u64>().unwrap_or(0),
+                false => 1,
+            };
+            let values = fields[1..=zones]
+                .iter()
+                .map(|f| f.parse::<f64>())
+                .collect::<Result<Vec<f64>, _>>();
+            match values {
+                Ok(values) => {
+                    for (channel, value) in values.into_iter().enumerate() {
+                        let moisture = percent(channel, value)
+                            .ok_or(anyhow!("{} has more zones than the config", path.display()))?;
+                        let zone = stats
+                            .zones
+                            .iter_mut()
+                            .find(|z| z.label == header[channel + 1])
+                            .unwrap();
+                        zone.min = zone.min.min(moisture);
+                        zone.max = zone.max.max(moisture);
+                        zone.sum += moisture * samples as f64;
+                        zone.samples += samples;
+                    }
+                    stats.polls += samples;
+                }
+                Err(_) if !hourly => stats.degraded += 1,
+                Err(_) => {}
+            }
+            stats.watering += water;
+        }
+    }
+    Ok(stats)
+}
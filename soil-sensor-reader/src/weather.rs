@@ -1,65 +1,652 @@
+use crate::{transient_http_error, HTTP_RETRY};
 use anyhow::{anyhow, Context, Result};
-use reqwest;
-use serde_json;
-use std::fs::File;
+use chrono::Utc;
+use lora_host_common::{metrics, secret::Secret};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::fmt;
+use std::fs::{self, OpenOptions};
 use std::io::Write;
-use std::path::Path;
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 15);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Forecasts older than this are not used for decisions anymore
+const MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Forecast {
+    /// Highest probability of precipitation over the next 6 hours, 0..1
+    pub precipitation_probability: f64,
+    /// Current wind speed in m/s
+    pub wind_speed: f64,
+    /// Highest temperature over the next 24 hours in °C
+    pub max_temperature: f64,
+    /// Rain and snow expected over the next 24 hours in mm
+    pub precipitation: f64,
+    /// Taken from the seasonal averages because no provider had a recent forecast
+    pub fallback: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Provider {
+    /// OpenWeather One Call, needs the token given on the command line
+    OpenWeather,
+    /// Open-Meteo, free for non-commercial use without a key
+    OpenMeteo,
+}
+
+impl Provider {
+    /// Name of the provider's fixture file, as it is spelled in the config
+    fn fixture_name(self) -> &'static str {
+        match self {
+            Provider::OpenWeather => "open_weather",
+            Provider::OpenMeteo => "open_meteo",
+        }
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Provider::OpenWeather => "OpenWeather",
+            Provider::OpenMeteo => "Open-Meteo",
+        })
+    }
+}
+
+/// Version of the OpenWeather One Call API, 2.5 is deprecated and closed to new keys
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub enum OneCallVersion {
+    #[default]
+    #[serde(rename = "2.5")]
+    V2_5,
+    #[serde(rename = "3.0")]
+    V3_0,
+}
+
+impl fmt::Display for OneCallVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OneCallVersion::V2_5 => "2.5",
+            OneCallVersion::V3_0 => "3.0",
+        })
+    }
+}
+
+/// How the forecasts of several providers are combined
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Blend {
+    /// The highest probability of precipitation, any provider expecting rain skips watering
+    #[default]
+    Max,
+    /// Probabilities averaged by the providers' weights, one provider alone does not cancel
+    /// watering
+    Weighted,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ProviderConfig {
+    pub provider: Provider,
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+/// Monthly averages standing in for the forecast while no provider has a recent one, January
+/// first
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FallbackConfig {
+    /// Probability of precipitation in percent
+    pub pop: [f64; 12],
+    /// Daily highest temperature in °C
+    pub max_temperature: [f64; 12],
+    /// Daily rain and snow in mm
+    pub precipitation: [f64; 12],
+}
+
+impl FallbackConfig {
+    fn forecast(&self, month: usize) -> Forecast {
+        Forecast {
+            precipitation_probability: self.pop[month] / 100.0,
+            // unknown, not worth skipping watering over
+            wind_speed: 0.0,
+            max_temperature: self.max_temperature[month],
+            precipitation: self.precipitation[month],
+            fallback: true,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WeatherConfig {
+    pub providers: Vec<ProviderConfig>,
+    #[serde(default)]
+    pub blend: Blend,
+    #[serde(default)]
+    pub open_weather_version: OneCallVersion,
+    /// Used while no provider has a forecast younger than an hour, including before the first
+    /// fetch finished, nothing is watered then without it
+    #[serde(default)]
+    pub fallback: Option<FallbackConfig>,
+    /// OpenWeather token when it is not given on the command line
+    #[serde(default)]
+    pub token: Option<Secret>,
+    /// Set from the command line
+    #[serde(skip)]
+    pub fixtures: Option<Fixtures>,
+}
+
+impl Default for WeatherConfig {
+    fn default() -> Self {
+        Self {
+            providers: vec![ProviderConfig {
+                provider: Provider::OpenWeather,
+                weight: default_weight(),
+            }],
+            blend: Blend::Max,
+            open_weather_version: OneCallVersion::V2_5,
+            fallback: None,
+            token: None,
+            fixtures: None,
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 struct WeatherData {
-    precipitation_probability: f64,
+    forecast: Forecast,
     timestamp: Instant,
 }
 
-pub struct Weather {
-    latitude: f64,
-    longitude: f64,
-    weather_token: String,
+/// Outcome of the refresh thread's fetches so far
+#[derive(Default)]
+struct Latest {
     data: Option<WeatherData>,
+    error: Option<String>,
+}
+
+struct Source {
+    provider: Provider,
+    weight: f64,
+    latest: Arc<Mutex<Latest>>,
+}
+
+impl Source {
+    /// The provider's forecast if it is younger than an hour, never waits for a fetch
+    fn forecast(&self) -> Result<Forecast> {
+        let latest = self.latest.lock().unwrap();
+        match (&latest.data, &latest.error) {
+            (Some(data), _) if data.timestamp.elapsed() < MAX_AGE => Ok(data.forecast),
+            (_, Some(e)) => Err(anyhow!("{}: {}", self.provider, e)),
+            (Some(_), None) => Err(anyhow!("{}: no recent forecast", self.provider)),
+            (None, None) => Err(anyhow!("{}: no forecast yet", self.provider)),
+        }
+    }
+}
+
+/// Forecasts kept up to date by a background thread per provider, so that a slow or hanging
+/// weather request never holds up sensor polling
+pub struct Weather {
+    sources: Vec<Source>,
+    blend: Blend,
+    fallback: Option<FallbackConfig>,
+    using_fallback: Cell<bool>,
 }
 
 impl Weather {
-    pub fn new(latitude: f64, longitude: f64, weather_token: String) -> Self {
+    /// Starts the refresh threads, they fetch right away and then every 15 minutes, retrying
+    /// failed fetches every minute
+    pub fn new(latitude: f64, longitude: f64, config: WeatherConfig) -> Self {
+        let weather_token = config
+            .token
+            .as_ref()
+            .map_or(String::new(), |t| t.expose().to_owned());
+        let sources = config
+            .providers
+            .into_iter()
+            .map(|p| {
+                let latest = Arc::new(Mutex::new(Latest::default()));
+                let shared = latest.clone();
+                let token = weather_token.clone();
+                let provider = p.provider;
+                let version = config.open_weather_version;
+                let transport = Transport::new(config.fixtures.as_ref(), provider);
+                thread::spawn(move || {
+                    let mut transport = transport;
+                    refresh(
+                        provider,
+                        latitude,
+                        longitude,
+                        &token,
+                        version,
+                        &mut transport,
+                        &shared,
+                    )
+                });
+                Source {
+                    provider,
+                    weight: p.weight,
+                    latest,
+                }
+            })
+            .collect();
         Self {
-            latitude,
-            longitude,
-            weather_token,
-            data: None,
+            sources,
+            blend: config.blend,
+            fallback: config.fallback,
+            using_fallback: Cell::new(false),
         }
     }
 
-    fn fetch_forecast(&self) -> Result<WeatherData, anyhow::Error> {
-        let url = format!(
-            "https://api.openweathermap.org/data/2.5/onecall?lat={}&lon={}&lang=en&units=metric&exclude=minutely,daily&appid={}",
-            self.latitude, self.longitude, self.weather_token
-        );
-        let response = reqwest::blocking::get(&url)?.json::<serde_json::Value>()?;
-
-        let mut pop = 0.0;
-        for i in 0..6 {
-            let p = response["hourly"][i]["pop"]
-                .as_f64()
-                .ok_or(anyhow!("pop not found in response"))?;
-            if p > pop {
-                pop = p;
+    /// The forecasts of the providers with one younger than an hour, blended. Without any the
+    /// seasonal averages for `month` (0 for January) stand in when configured, otherwise this
+    /// fails.
+    pub fn get_forecast(&self, month: usize) -> Result<Forecast, anyhow::Error> {
+        let mut forecasts = Vec::new();
+        let mut errors = Vec::new();
+        for source in &self.sources {
+            match source.forecast() {
+                Ok(forecast) => forecasts.push((source.weight, forecast)),
+                Err(e) => errors.push(format!("{:#}", e)),
             }
         }
-
-        Ok(WeatherData {
-            precipitation_probability: pop,
-            timestamp: Instant::now(),
+        let error = anyhow!("No recent forecast: {}", errors.join("; "));
+        match (forecasts.is_empty(), &self.fallback) {
+            (true, None) => return Err(error),
+            (true, Some(fallback)) => {
+                if !self.using_fallback.replace(true) {
+                    warn!("{:#}, using the seasonal averages", error);
+                }
+                return Ok(fallback.forecast(month));
+            }
+            (false, _) => {
+                if self.using_fallback.replace(false) {
+                    info!("Forecasts available again, leaving the seasonal averages");
+                }
+            }
+        }
+        let highest = |value: fn(&Forecast) -> f64| {
+            forecasts
+                .iter()
+                .map(|(_, f)| value(f))
+                .fold(f64::NEG_INFINITY, f64::max)
+        };
+        let blend = |value: fn(&Forecast) -> f64| match self.blend {
+            Blend::Max => highest(value),
+            Blend::Weighted => {
+                let total = forecasts.iter().map(|(w, _)| w).sum::<f64>();
+                forecasts.iter().map(|(w, f)| w * value(f)).sum::<f64>() / total
+            }
+        };
+        Ok(Forecast {
+            precipitation_probability: blend(|f| f.precipitation_probability),
+            // the strongest wind, spray drift is not worth averaging away
+            wind_speed: highest(|f| f.wind_speed),
+            max_temperature: blend(|f| f.max_temperature),
+            precipitation: blend(|f| f.precipitation),
+            fallback: false,
         })
     }
+}
+
+/// Refresh loop of one provider, logging when it starts failing and when it recovers
+fn refresh(
+    provider: Provider,
+    latitude: f64,
+    longitude: f64,
+    weather_token: &str,
+    version: OneCallVersion,
+    transport: &mut Transport,
+    shared: &Mutex<Latest>,
+) {
+    let mut failures = 0u32;
+    loop {
+        let result = match provider {
+            Provider::OpenWeather => {
+                fetch_forecast(transport, latitude, longitude, weather_token, version)
+            }
+            Provider::OpenMeteo => fetch_open_meteo(transport, latitude, longitude),
+        };
+        let delay = match result.is_ok() {
+            true => REFRESH_INTERVAL,
+            false => RETRY_INTERVAL,
+        };
+        {
+            let mut latest = shared.lock().unwrap();
+            match result {
+                Ok(data) => {
+                    if failures > 0 {
+                        info!(
+                            "{} forecast available again after {} failed fetches",
+                            provider, failures
+                        );
+                    }
+                    failures = 0;
+                    latest.data = Some(data);
+                    latest.error = None;
+                }
+                Err(e) => {
+                    failures += 1;
+                    metrics::increment("weather.fetch_failures");
+                    warn!("Failed to fetch the {} forecast: {:#}", provider, e);
+                    latest.error = Some(format!("{:#}", e));
+                }
+            }
+        }
+        thread::sleep(delay);
+    }
+}
+
+/// Provider responses recorded to or replayed from a directory holding a `<provider>.jsonl`
+/// file per provider, e.g. `open_meteo.jsonl`, so forecasts can be reproduced in tests and dry
+/// runs
+#[derive(Clone, Debug)]
+pub enum Fixtures {
+    /// Append every response to the files, fetching as usual
+    Record(PathBuf),
+    /// Serve the recorded responses in order instead of fetching, the last one again once
+    /// all were served
+    Replay(PathBuf),
+}
+
+/// A recorded response, without the URL as it can carry the token
+#[derive(Serialize, Deserialize)]
+struct Interaction {
+    recorded_at: String,
+    status: u16,
+    body: String,
+}
+
+/// Where a refresh thread gets its provider's responses from
+enum Transport {
+    Http,
+    Record(PathBuf),
+    Replay { path: PathBuf, next: usize },
+}
+
+impl Transport {
+    fn new(fixtures: Option<&Fixtures>, provider: Provider) -> Self {
+        let file = |dir: &Path| dir.join(format!("{}.jsonl", provider.fixture_name()));
+        match fixtures {
+            None => Transport::Http,
+            Some(Fixtures::Record(dir)) => Transport::Record(file(dir)),
+            Some(Fixtures::Replay(dir)) => Transport::Replay {
+                path: file(dir),
+                next: 0,
+            },
+        }
+    }
 
-    pub fn get_precipitation_probability(&mut self) -> Result<f64, anyhow::Error> {
-        if let Some(data) = &self.data {
-            if data.timestamp.elapsed().as_secs() < 60 * 15 {
-                return Ok(data.precipitation_probability);
+    /// The body of a successful response, errors leave out the URL as it can carry the token
+    fn get(&mut self, url: &str) -> Result<String> {
+        let record = match self {
+            Transport::Http => None,
+            Transport::Record(path) => Some(path.as_path()),
+            Transport::Replay { path, next } => return replay(path, next),
+        };
+        let (body, _) = HTTP_RETRY.run_if(transient_http_error, |_| {
+            let response = reqwest::blocking::get(url).map_err(reqwest::Error::without_url)?;
+            let status = response.status().as_u16();
+            let error = response.error_for_status_ref().err();
+            let body = response.text().map_err(reqwest::Error::without_url)?;
+            if let Some(path) = record {
+                append(path, status, &body)?;
             }
+            match error {
+                Some(e) => Err(e.without_url().into()),
+                None => Ok(body),
+            }
+        });
+        body
+    }
+}
+
+fn append(path: &Path, status: u16, body: &str) -> Result<()> {
+    let line = serde_json::to_string(&Interaction {
+        recorded_at: Utc::now().to_rfc3339(),
+        status,
+        body: body.to_owned(),
+    })?;
+    fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))
+        .and_then(|_| OpenOptions::new().create(true).append(true).open(path))
+        .and_then(|mut file| writeln!(file, "{}", line))
+        .with_context(|| format!("Failed to record the response to {}", path.display()))
+}
+
+/// The `next` recorded response, an error one fails like the provider did
+fn replay(path: &Path, next: &mut usize) -> Result<String> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the recorded responses {}", path.display()))?;
+    let mut interactions = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str::<Interaction>)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse the recorded responses {}", path.display()))?;
+    let last = interactions
+        .len()
+        .checked_sub(1)
+        .ok_or(anyhow!("No responses are recorded in {}", path.display()))?;
+    let interaction = interactions.swap_remove((*next).min(last));
+    *next += 1;
+    match interaction.status {
+        200..=299 => Ok(interaction.body),
+        status => Err(anyhow!(
+            "HTTP status {} recorded in {}: {}",
+            status,
+            path.display(),
+            interaction.body
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+struct OneCall {
+    current: OneCallCurrent,
+    hourly: Vec<OneCallHour>,
+}
+
+#[derive(Deserialize)]
+struct OneCallCurrent {
+    wind_speed: f64,
+}
+
+#[derive(Deserialize)]
+struct OneCallHour {
+    temp: f64,
+    pop: f64,
+    /// Left out for hours without rain or snow
+    #[serde(default)]
+    rain: Option<OneCallVolume>,
+    #[serde(default)]
+    snow: Option<OneCallVolume>,
+}
+
+#[derive(Deserialize)]
+struct OneCallVolume {
+    #[serde(rename = "1h")]
+    last_hour: f64,
+}
+
+fn fetch_forecast(
+    transport: &mut Transport,
+    latitude: f64,
+    longitude: f64,
+    weather_token: &str,
+    version: OneCallVersion,
+) -> Result<WeatherData, anyhow::Error> {
+    let url = format!(
+        "https://api.openweathermap.org/data/{}/onecall?lat={}&lon={}&lang=en&units=metric&exclude=minutely,daily&appid={}",
+        version, latitude, longitude, weather_token
+    );
+    let response = serde_json::from_str::<OneCall>(&transport.get(&url)?)
+        .context("Failed to parse the OpenWeather response")?;
+    if response.hourly.len() < 6 {
+        return Err(anyhow!(
+            "Expected an hourly forecast for at least 6 hours, got {}",
+            response.hourly.len()
+        ));
+    }
+
+    let next_day = &response.hourly[..response.hourly.len().min(24)];
+    let volume = |v: &Option<OneCallVolume>| v.as_ref().map_or(0.0, |v| v.last_hour);
+    Ok(WeatherData {
+        forecast: Forecast {
+            precipitation_probability: response.hourly[..6]
+                .iter()
+                .map(|h| h.pop)
+                .fold(0.0, f64::max),
+            wind_speed: response.current.wind_speed,
+            max_temperature: next_day
+                .iter()
+                .map(|h| h.temp)
+                .fold(f64::NEG_INFINITY, f64::max),
+            precipitation: next_day
+                .iter()
+                .map(|h| volume(&h.rain) + volume(&h.snow))
+                .sum(),
+            fallback: false,
+        },
+        timestamp: Instant::now(),
+    })
+}
+
+#[derive(Deserialize)]
+struct OpenMeteo {
+    current: OpenMeteoCurrent,
+    hourly: OpenMeteoHourly,
+}
+
+#[derive(Deserialize)]
+struct OpenMeteoCurrent {
+    wind_speed_10m: f64,
+}
+
+/// Values per hour, null for hours the model has none for
+#[derive(Deserialize)]
+struct OpenMeteoHourly {
+    /// In percent
+    precipitation_probability: Vec<Option<f64>>,
+    temperature_2m: Vec<Option<f64>>,
+    precipitation: Vec<Option<f64>>,
+}
+
+fn fetch_open_meteo(
+    transport: &mut Transport,
+    latitude: f64,
+    longitude: f64,
+) -> Result<WeatherData> {
+    let url = format!(
+        "https://api.open-meteo.com/v1/forecast?latitude={}&longitude={}&hourly=precipitation_probability,temperature_2m,precipitation&current=wind_speed_10m&wind_speed_unit=ms&forecast_hours=24",
+        latitude, longitude
+    );
+    let response = serde_json::from_str::<OpenMeteo>(&transport.get(&url)?)
+        .context("Failed to parse the Open-Meteo response")?;
+    let hourly = &response.hourly;
+
+    // over the same 6 hours as OpenWeather
+    let pop = hourly
+        .precipitation_probability
+        .iter()
+        .take(6)
+        .flatten()
+        .fold(0.0, |a: f64, b| a.max(*b))
+        / 100.0;
+    let wind_speed = response.current.wind_speed_10m;
+    let max_temperature = hourly
+        .temperature_2m
+        .iter()
+        .flatten()
+        .cloned()
+        .reduce(f64::max)
+        .ok_or(anyhow!("No temperature in the forecast"))?;
+    let precipitation = hourly.precipitation.iter().flatten().sum();
+
+    Ok(WeatherData {
+        forecast: Forecast {
+            precipitation_probability: pop,
+            wind_speed,
+            max_temperature,
+            precipitation,
+            fallback: false,
+        },
+        timestamp: Instant::now(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replaying(provider: Provider) -> Transport {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/weather");
+        Transport::new(Some(&Fixtures::Replay(dir)), provider)
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn open_weather_replays_in_order() {
+        let mut transport = replaying(Provider::OpenWeather);
+        let forecast = fetch_forecast(&mut transport, 50.0755, 14.4378, "", OneCallVersion::V3_0)
+            .unwrap()
+            .forecast;
+        assert_close(forecast.precipitation_probability, 0.62);
+        assert_close(forecast.wind_speed, 3.6);
+        assert_close(forecast.max_temperature, 21.3);
+        assert_close(forecast.precipitation, 0.31 + 1.2 + 0.18);
+
+        // the key was revoked before the second fetch, it stays revoked
+        for _ in 0..2 {
+            let e = fetch_forecast(&mut transport, 50.0755, 14.4378, "", OneCallVersion::V3_0)
+                .unwrap_err();
+            assert!(format!("{:#}", e).contains("HTTP status 401"), "{:#}", e);
         }
-        let data = self.fetch_forecast()?;
-        self.data = Some(data);
-        Ok(data.precipitation_probability)
+    }
+
+    #[test]
+    fn open_meteo_skips_hours_without_values() {
+        let forecast = fetch_open_meteo(&mut replaying(Provider::OpenMeteo), 50.0755, 14.4378)
+            .unwrap()
+            .forecast;
+        assert_close(forecast.precipitation_probability, 0.48);
+        assert_close(forecast.wind_speed, 2.9);
+        assert_close(forecast.max_temperature, 20.2);
+        assert_close(forecast.precipitation, 1.4);
+    }
+
+    #[test]
+    fn recorded_responses_replay() {
+        let dir = std::env::temp_dir().join(format!("weather-fixtures-{}", std::process::id()));
+        let fixtures = Fixtures::Record(dir.clone());
+        let Transport::Record(path) = Transport::new(Some(&fixtures), Provider::OpenMeteo) else {
+            panic!("not recording");
+        };
+        append(&path, 200, "first").unwrap();
+        append(&path, 503, "busy").unwrap();
+
+        let mut transport =
+            Transport::new(Some(&Fixtures::Replay(dir.clone())), Provider::OpenMeteo);
+        assert_eq!(transport.get("").unwrap(), "first");
+        assert!(transport.get("").is_err());
+        fs::remove_dir_all(dir).unwrap();
     }
 }